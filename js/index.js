@@ -1 +1,6 @@
-import("../pkg/index.js").catch(console.error);
+import("../pkg/index.js")
+  .then((wasm) => {
+    const game = new wasm.WalkTheDogHandle();
+    return game.start({ autofocus: true });
+  })
+  .catch(console.error);
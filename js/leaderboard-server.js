@@ -0,0 +1,50 @@
+// ランキングサーバーの代わりにローカルで動かす小さなサーバー。記録はメモリにだけ持つ
+const http = require("http");
+
+const port = Number(process.env.PORT || 8787);
+const runs = [];
+
+const send = (res, status, body) => {
+  res.writeHead(status, {
+    "Content-Type": "application/json",
+    "Access-Control-Allow-Origin": "*",
+    "Access-Control-Allow-Headers": "Content-Type",
+  });
+  res.end(JSON.stringify(body));
+};
+
+const top = (count) =>
+  runs
+    .map(({ score, seed, date }) => ({ score, seed, date }))
+    .sort((a, b) => b.score - a.score)
+    .slice(0, count);
+
+http
+  .createServer((req, res) => {
+    const url = new URL(req.url, `http://localhost:${port}`);
+
+    if (req.method === "OPTIONS") {
+      send(res, 204, {});
+    } else if (req.method === "POST" && url.pathname === "/runs") {
+      let body = "";
+      req.on("data", (chunk) => (body += chunk));
+      req.on("end", () => {
+        try {
+          const run = JSON.parse(body);
+          if (typeof run.score !== "number" || typeof run.seed !== "number") {
+            send(res, 400, { error: "score and seed are required" });
+            return;
+          }
+          runs.push(run);
+          send(res, 201, { rank: top(runs.length).findIndex((entry) => entry.score === run.score) + 1 });
+        } catch (err) {
+          send(res, 400, { error: err.message });
+        }
+      });
+    } else if (req.method === "GET" && url.pathname === "/runs/top") {
+      send(res, 200, top(Number(url.searchParams.get("count") || 10)));
+    } else {
+      send(res, 404, { error: "not found" });
+    }
+  })
+  .listen(port, () => console.log(`Leaderboard stand-in listening on http://localhost:${port}`));
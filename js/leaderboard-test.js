@@ -0,0 +1,31 @@
+// ランキングサーバーの代わりをテスト用のポートで立ててから、ブラウザでwasmのテストを走らせる
+const { spawn } = require("child_process");
+const path = require("path");
+
+const port = "8788";
+// npm test -- --firefox のように渡されたブラウザで走らせる。無ければChrome
+const browsers = process.argv.length > 2 ? process.argv.slice(2) : ["--chrome"];
+
+const server = spawn(process.execPath, [path.join(__dirname, "leaderboard-server.js")], {
+  env: { ...process.env, PORT: port },
+  stdio: ["ignore", "pipe", "inherit"],
+});
+
+const finish = (code) => {
+  server.kill();
+  process.exit(code);
+};
+
+server.on("exit", (code) => {
+  console.error(`Leaderboard stand-in exited with ${code}`);
+  process.exit(1);
+});
+
+server.stdout.once("data", (line) => {
+  process.stdout.write(line);
+  const tests = spawn("wasm-pack", ["test", "--headless", ...browsers], { stdio: "inherit", shell: true });
+  tests.on("exit", (code) => {
+    server.removeAllListeners("exit");
+    finish(code ?? 1);
+  });
+});
@@ -1,12 +1,13 @@
 /* ブラウザとやりとりするためのコード */
 use anyhow::{anyhow,Result};
-use std::{future::Future};
+use std::{collections::HashMap, future::Future};
 
 use wasm_bindgen::{
     closure::WasmClosure, closure::WasmClosureFnOnce, prelude::Closure, JsCast, JsValue,
 };
 use wasm_bindgen_futures::JsFuture;
-use web_sys::{Window, Document, HtmlCanvasElement, CanvasRenderingContext2d, Response, HtmlImageElement, Element, HtmlElement};
+use web_sys::{Window, Document, Event, EventTarget, HtmlCanvasElement, CanvasRenderingContext2d, Response, HtmlImageElement, Element, HtmlElement, Headers, RequestInit};
+use serde::Serialize;
 use js_sys::ArrayBuffer;
 
 macro_rules! log {
@@ -23,17 +24,22 @@ pub fn document() -> Result<Document> {
     window()?.document().ok_or_else(|| anyhow!("No document found."))
 }
 
-// canvasがハードコーディングされているが一旦このままで問題が起きたら修正する
-pub fn canvas() -> Result<HtmlCanvasElement>{
+pub fn canvas(id: &str) -> Result<HtmlCanvasElement>{
+    find_element(id)?
+        .dyn_into::<HtmlCanvasElement>()
+        .map_err(|element| anyhow!("Error converting {:#?} to HtmlCanvasElement", element))
+}
+
+pub fn create_canvas() -> Result<HtmlCanvasElement>{
     document()?
-        .get_element_by_id("canvas")
-        .ok_or_else(|| anyhow!("No canvas Element found with id 'canvas'"))?
+        .create_element("canvas")
+        .map_err(|err| anyhow!("Could not create canvas element {:#?}", err))?
         .dyn_into::<HtmlCanvasElement>()
         .map_err(|element| anyhow!("Error converting {:#?} to HtmlCanvasElement", element))
 }
 
-pub fn context() -> Result<CanvasRenderingContext2d>{
-    canvas()?
+pub fn context_2d(canvas: &HtmlCanvasElement) -> Result<CanvasRenderingContext2d>{
+    canvas
         .get_context("2d")
         .map_err(|js_value| anyhow!("Error getting 2d context {:#?}", js_value))?
         .ok_or_else(|| anyhow!("2d context not found"))?
@@ -43,6 +49,23 @@ pub fn context() -> Result<CanvasRenderingContext2d>{
         })
 }
 
+pub fn debug_mode() -> bool{
+    window()
+        .and_then(|window| {
+            window
+                .location()
+                .search()
+                .map_err(|err| anyhow!("Could not read location {:#?}", err))
+        })
+        .and_then(|search| {
+            web_sys::UrlSearchParams::new_with_str(&search)
+                .map_err(|err| anyhow!("Could not parse the query string {:#?}", err))
+        })
+        // ?debug や ?debug=1 だけを見る。?nodebug などでは付けない
+        .map(|params| params.has("debug"))
+        .unwrap_or(false)
+}
+
 pub fn spawn_local<F>(future: F)
 where 
     F: Future<Output = ()> + 'static,
@@ -50,6 +73,13 @@ where
     wasm_bindgen_futures::spawn_local(future);
 }
 
+pub fn future_to_promise<F>(future: F) -> js_sys::Promise
+where
+    F: Future<Output = Result<JsValue, JsValue>> + 'static,
+{
+    wasm_bindgen_futures::future_to_promise(future)
+}
+
 pub async fn fetch_with_str(resource: &str) -> Result<JsValue>{
     JsFuture::from(window()?.fetch_with_str(resource))
         .await
@@ -66,6 +96,33 @@ pub async fn fetch_json(json_path: &str) -> Result<JsValue>{
     .map_err(|err| anyhow!("Failed to parse JSON {:#?}", err))
 }
 
+// JSONにしたbodyをPOSTして、返ってきたJSONを読む
+pub async fn post_json<T: Serialize>(resource: &str, body: &T) -> Result<JsValue>{
+    let headers = Headers::new().map_err(|err| anyhow!("Could not create Headers {:#?}", err))?;
+    headers
+        .set("Content-Type", "application/json")
+        .map_err(|err| anyhow!("Could not set Content-Type {:#?}", err))?;
+    let init = RequestInit::new();
+    init.set_method("POST");
+    init.set_headers(&headers);
+    init.set_body(&JsValue::from_str(&serde_json::to_string(body)?));
+
+    let resp: Response = JsFuture::from(window()?.fetch_with_str_and_init(resource, &init))
+        .await
+        .map_err(|err| anyhow!("Failed to post to {} {:#?}", resource, err))?
+        .dyn_into()
+        .map_err(|err| anyhow!("Error converting {:#?} to Response", err))?;
+    if !resp.ok() {
+        return Err(anyhow!("Post to {} failed with status {}", resource, resp.status()));
+    }
+
+    JsFuture::from(
+        resp.json().map_err(|err| anyhow!("Failed to call json() on Response {:#?}", err))?,
+    )
+    .await
+    .map_err(|err| anyhow!("Failed to parse JSON {:#?}", err))
+}
+
 pub async fn fetch_array_buffer(resource: &str) -> Result<ArrayBuffer>{
     let array_buffer = fetch_responce(resource)
         .await?
@@ -80,10 +137,14 @@ pub async fn fetch_array_buffer(resource: &str) -> Result<ArrayBuffer>{
 }
 
 pub async fn fetch_responce(resource: &str) -> Result<Response>{
-    fetch_with_str(resource)
+    let resp: Response = fetch_with_str(resource)
         .await?
         .dyn_into()
-        .map_err(|err| anyhow!("Error converting {:#?} to Response", err))
+        .map_err(|err| anyhow!("Error converting {:#?} to Response", err))?;
+    if !resp.ok() {
+        return Err(anyhow!("Fetching {} failed with status {}", resource, resp.status()));
+    }
+    Ok(resp)
 }
 
 pub fn new_image() -> Result<HtmlImageElement>{
@@ -106,6 +167,12 @@ pub fn request_animation_frame(callback: &LoopClosure) -> Result<i32>{
         .map_err(|err| anyhow!("Failed to request animation frame {:#?}", err))
 }
 
+pub fn cancel_animation_frame(id: i32) -> Result<()>{
+    window()?
+        .cancel_animation_frame(id)
+        .map_err(|err| anyhow!("Failed to cancel animation frame {:#?}", err))
+}
+
 pub fn create_ref_closure(f: impl FnMut(f64) + 'static) -> LoopClosure {
     closure_wrap(Box::new(f))
 }
@@ -114,6 +181,51 @@ pub fn closure_wrap<T: WasmClosure + ?Sized>(data: Box<T>) -> Closure<T>{
     Closure::wrap(data)
 }
 
+// addEventListenerで付けたリスナー。捨てられるとremoveEventListenerで外してClosureも解放する
+struct Listener{
+    target: EventTarget,
+    event: &'static str,
+    closure: Closure<dyn FnMut(Event)>,
+}
+
+impl Drop for Listener{
+    fn drop(&mut self){
+        if let Err(err) = self
+            .target
+            .remove_event_listener_with_callback(self.event, self.closure.as_ref().unchecked_ref())
+        {
+            log!("Could not remove {} listener {:#?}", self.event, err);
+        }
+    }
+}
+
+// リスナーの持ち主(画面やゲームループ)が持っておき、持ち主と一緒に捨てる
+#[derive(Default)]
+pub struct Listeners{
+    listeners: Vec<Listener>,
+}
+
+impl Listeners{
+    pub fn add<E: JsCast + 'static>(
+        &mut self,
+        target: &EventTarget,
+        event: &'static str,
+        mut callback: impl FnMut(E) + 'static,
+    ) -> Result<()>{
+        let closure = closure_wrap(Box::new(move |evt: Event| callback(evt.unchecked_into::<E>()))
+            as Box<dyn FnMut(Event)>);
+        target
+            .add_event_listener_with_callback(event, closure.as_ref().unchecked_ref())
+            .map_err(|err| anyhow!("Could not add {} listener {:#?}", event, err))?;
+        self.listeners.push(Listener{
+            target: target.clone(),
+            event,
+            closure,
+        });
+        Ok(())
+    }
+}
+
 pub fn now() -> Result<f64>{
     Ok(window()?
         .performance()
@@ -121,45 +233,167 @@ pub fn now() -> Result<f64>{
         .now())
 }
 
-pub fn draw_ui(html: &str) -> Result<()>{
-    find_ui()?
-        .insert_adjacent_html("afterbegin", html)
-        .map_err(|err| anyhow!("Could not insert html {:#?}", err))
+// 今日の日付(YYYY-MM-DD)
+pub fn today() -> String{
+    let iso: String = js_sys::Date::new_0().to_iso_string().into();
+    iso.chars().take(10).collect()
 }
 
-pub fn hide_ui() -> Result<()>{
-    let ui = find_ui()?;
+// 文字列を保存しておく場所。ブラウザではlocalStorage、テストではメモリを使う
+pub trait Storage {
+    fn get(&mut self, key: &str) -> Result<Option<String>>;
+    fn set(&mut self, key: &str, value: &str) -> Result<()>;
+}
+
+pub struct LocalStorage{
+    storage: web_sys::Storage,
+}
 
-    if let Some(child) = ui.first_child() {
-        ui.remove_child(&child)
-            .map(|_removed_child| ())
-            .map_err(|err| anyhow!("Failed to remove child {:#?}", err))
-            .and_then(|_unit| {
-                canvas()?
-                    .focus()
-                    .map_err(|err| anyhow!("Could not set focus to canvas {:#?}", err))
-            })
-    } else {
+impl LocalStorage{
+    pub fn new() -> Result<Self>{
+        let storage = window()?
+            .local_storage()
+            .map_err(|err| anyhow!("Could not access localStorage {:#?}", err))?
+            .ok_or_else(|| anyhow!("No localStorage found"))?;
+        Ok(LocalStorage { storage })
+    }
+}
+
+impl Storage for LocalStorage{
+    fn get(&mut self, key: &str) -> Result<Option<String>>{
+        self.storage
+            .get_item(key)
+            .map_err(|err| anyhow!("Could not read {} from localStorage {:#?}", key, err))
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<()>{
+        self.storage
+            .set_item(key, value)
+            .map_err(|err| anyhow!("Could not write {} to localStorage {:#?}", key, err))
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryStorage{
+    items: HashMap<String, String>,
+}
+
+impl Storage for MemoryStorage{
+    fn get(&mut self, key: &str) -> Result<Option<String>>{
+        Ok(self.items.get(key).cloned())
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<()>{
+        self.items.insert(key.to_string(), value.to_string());
         Ok(())
     }
 }
 
-fn find_ui() -> Result<Element> {
+// キーの前にnamespaceを付けて、同じページのゲーム同士で保存先が混ざらないようにする
+pub struct NamespacedStorage<S: Storage>{
+    namespace: String,
+    storage: S,
+}
+
+impl<S: Storage> NamespacedStorage<S>{
+    pub fn new(namespace: &str, storage: S) -> Self{
+        NamespacedStorage { namespace: namespace.to_string(), storage }
+    }
+
+    fn key(&self, key: &str) -> String{
+        format!("{}:{}", self.namespace, key)
+    }
+}
+
+impl<S: Storage> Storage for NamespacedStorage<S>{
+    // namespaceを付ける前に保存されたデータは、初めて読んだときに今のキーへ写す
+    fn get(&mut self, key: &str) -> Result<Option<String>>{
+        let namespaced = self.key(key);
+        if let Some(value) = self.storage.get(&namespaced)? {
+            return Ok(Some(value));
+        }
+        let legacy = self.storage.get(key)?;
+        if let Some(value) = &legacy {
+            self.storage.set(&namespaced, value)?;
+        }
+        Ok(legacy)
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<()>{
+        let key = self.key(key);
+        self.storage.set(&key, value)
+    }
+}
+
+// localStorageが使えない(プライベートブラウズなど)ときはメモリに保存する
+pub fn storage(namespace: &str) -> Box<dyn Storage>{
+    match LocalStorage::new() {
+        Ok(storage) => Box::new(NamespacedStorage::new(namespace, storage)),
+        Err(err) => {
+            log!("Falling back to in-memory storage {:#?}", err);
+            Box::new(MemoryStorage::default())
+        }
+    }
+}
+
+pub fn find_element(id: &str) -> Result<Element> {
     document().and_then(|doc| {
-        doc.get_element_by_id("ui")
-            .ok_or_else(|| anyhow!("UI element not found"))
+        doc.get_element_by_id(id)
+            .ok_or_else(|| anyhow!("No Element found with id '{}'", id))
     })
 }
 
-pub fn find_html_element_by_id(id: &str) -> Result<HtmlElement> {
+pub fn find_html_element(id: &str) -> Result<HtmlElement> {
+    find_element(id)?
+        .dyn_into::<HtmlElement>()
+        .map_err(|element| anyhow!("Error converting {:#?} to HtmlElement", element))
+}
+
+// elementにフォーカスがあるか
+pub fn has_focus(element: &HtmlElement) -> bool {
     document()
-        .and_then(|doc| {
-            doc.get_element_by_id(id)
-                .ok_or_else(|| anyhow!("Element with id {} not found", id))
-        })
-        .and_then(|element| {
-            element
-                .dyn_into::<HtmlElement>()
-                .map_err(|err| anyhow!("Could not cast into HtmlElement {:#?}", err))
-        })
-}
\ No newline at end of file
+        .ok()
+        .and_then(|doc| doc.active_element())
+        .map_or(false, |active| active.is_same_node(Some(element)))
+}
+
+pub fn create_html_element(tag: &str) -> Result<HtmlElement> {
+    document()?
+        .create_element(tag)
+        .map_err(|err| anyhow!("Could not create element {} {:#?}", tag, err))?
+        .dyn_into::<HtmlElement>()
+        .map_err(|element| anyhow!("Error converting {:#?} to HtmlElement", element))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn namespaced_keys_do_not_collide() {
+        let mut storage = NamespacedStorage::new("canvas", MemoryStorage::default());
+        storage.set("walk_the_dog.settings", "a").unwrap();
+
+        assert_eq!(storage.get("walk_the_dog.settings").unwrap(), Some("a".to_string()));
+        assert_eq!(storage.storage.get("canvas:walk_the_dog.settings").unwrap(), Some("a".to_string()));
+        assert_eq!(storage.storage.get("walk_the_dog.settings").unwrap(), None);
+
+        // 同じlocalStorageを使う別のゲームからは見えない
+        let mut other = NamespacedStorage::new("tutorial-canvas", storage.storage);
+        assert_eq!(other.get("walk_the_dog.settings").unwrap(), None);
+    }
+
+    #[test]
+    fn keys_saved_before_namespacing_are_migrated() {
+        let mut old = MemoryStorage::default();
+        old.set("walk_the_dog.high_scores", "[1]").unwrap();
+        let mut storage = NamespacedStorage::new("canvas", old);
+
+        assert_eq!(storage.get("walk_the_dog.high_scores").unwrap(), Some("[1]".to_string()));
+        assert_eq!(storage.storage.get("canvas:walk_the_dog.high_scores").unwrap(), Some("[1]".to_string()));
+
+        // 写したあとは新しいキーの値が優先される
+        storage.set("walk_the_dog.high_scores", "[2]").unwrap();
+        assert_eq!(storage.get("walk_the_dog.high_scores").unwrap(), Some("[2]".to_string()));
+    }
+}
@@ -0,0 +1,140 @@
+/* ページ側(JavaScript)からゲームを操作するための窓口。
+   WalkTheDogHandleとゲームで同じControlを共有し、命令はキューで、出来事はチャネルで受け渡す */
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use serde::{Deserialize, Serialize};
+use std::{
+    cell::{Cell, RefCell},
+    collections::VecDeque,
+    rc::Rc,
+};
+
+// start(config)で渡す設定
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, rename_all = "camelCase")]
+pub struct GameConfig {
+    // 最初の走りのシード。無ければランダム
+    pub seed: Option<u32>,
+    pub leaderboard_url: Option<String>,
+    // ゲームを置く要素のid。ゲームごとに変えれば1つのページに並べられる
+    pub canvas_id: String,
+    pub ui_id: String,
+    // キー入力を受け取る要素のid。無ければcanvas
+    pub input_id: Option<String>,
+    // localStorageのキーに付ける名前。無ければcanvas_id
+    pub storage_namespace: Option<String>,
+    // 始めたときにキー入力の要素へフォーカスを移す。ゲームが1つだけのページ向け
+    pub autofocus: bool,
+}
+
+impl Default for GameConfig {
+    fn default() -> Self {
+        GameConfig {
+            seed: None,
+            leaderboard_url: None,
+            canvas_id: "canvas".to_string(),
+            ui_id: "ui".to_string(),
+            input_id: None,
+            storage_namespace: None,
+            autofocus: false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Command {
+    Pause,
+    Resume,
+    // シードが無ければランダム
+    Restart(Option<u32>),
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum GameEvent {
+    Started { seed: u32 },
+    Paused,
+    Resumed,
+    GameOver { score: u32, seed: u32 },
+}
+
+#[derive(Clone)]
+pub struct Control {
+    commands: Rc<RefCell<VecDeque<Command>>>,
+    events: UnboundedSender<GameEvent>,
+    score: Rc<Cell<u32>>,
+}
+
+impl Control {
+    pub fn new() -> (Self, UnboundedReceiver<GameEvent>) {
+        let (events, receiver) = unbounded();
+        let control = Control {
+            commands: Rc::new(RefCell::new(VecDeque::new())),
+            events,
+            score: Rc::new(Cell::new(0)),
+        };
+        (control, receiver)
+    }
+
+    pub fn send(&self, command: Command) {
+        self.commands.borrow_mut().push_back(command);
+    }
+
+    pub fn next_command(&self) -> Option<Command> {
+        self.commands.borrow_mut().pop_front()
+    }
+
+    pub fn emit(&self, event: GameEvent) {
+        if let Err(err) = self.events.unbounded_send(event) {
+            log!("Could not send game event {:#?}", err);
+        }
+    }
+
+    pub fn score(&self) -> u32 {
+        self.score.get()
+    }
+
+    pub fn set_score(&self, score: u32) {
+        self.score.set(score);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands_come_out_in_the_order_they_were_sent() {
+        let (control, _events) = Control::new();
+        let game = control.clone();
+
+        control.send(Command::Pause);
+        control.send(Command::Restart(Some(7)));
+
+        assert_eq!(game.next_command(), Some(Command::Pause));
+        assert_eq!(game.next_command(), Some(Command::Restart(Some(7))));
+        assert_eq!(game.next_command(), None);
+    }
+
+    #[test]
+    fn events_and_score_reach_the_handle() {
+        let (control, mut events) = Control::new();
+        let game = control.clone();
+
+        game.emit(GameEvent::GameOver { score: 120, seed: 3 });
+        game.set_score(120);
+
+        assert_eq!(events.try_next().unwrap(), Some(GameEvent::GameOver { score: 120, seed: 3 }));
+        assert_eq!(control.score(), 120);
+    }
+
+    #[test]
+    fn config_fields_are_optional() {
+        let config: GameConfig = serde_json::from_str(r#"{"seed": 42}"#).unwrap();
+
+        assert_eq!(config.seed, Some(42));
+        assert_eq!(config.leaderboard_url, None);
+        assert_eq!(config.canvas_id, "canvas");
+        assert_eq!(config.storage_namespace, None);
+        assert!(!config.autofocus);
+    }
+}
@@ -5,14 +5,18 @@ use futures::channel::{
 };
 
 use serde::Deserialize;
-use std::{collections::HashMap, rc::Rc};
+use std::{borrow::Cow, collections::{HashMap, HashSet}, rc::Rc};
 use std::sync::Mutex;
 use std::cell::RefCell;
 
 use wasm_bindgen::JsCast;
 use wasm_bindgen::prelude::{Closure, JsValue};
-use web_sys::{CanvasRenderingContext2d, HtmlImageElement, Response, AudioContext, AudioBuffer, HtmlElement};
+use web_sys::{
+    AudioBuffer, AudioContext, CanvasRenderingContext2d, Gamepad, GamepadButton, HtmlCanvasElement,
+    Element, GainNode, HtmlElement, HtmlImageElement, MouseEvent,
+};
 
+use crate::browser::Listeners;
 use crate::sound;
 
 use crate::browser::{self, LoopClosure};
@@ -26,7 +30,7 @@ pub async fn load_image(source: &str) -> Result<HtmlImageElement> {
     let success_callback = browser::closure_once(move ||{
         if let Some(success_tx) = success_tx.lock().ok()
             .and_then(|mut opt| opt.take()){
-                success_tx.send(Ok(()));
+                let _ = success_tx.send(Ok(()));
             }
     });
 
@@ -34,7 +38,7 @@ pub async fn load_image(source: &str) -> Result<HtmlImageElement> {
         browser::closure_once(move |err|{
             if let Some(error_tx) = error_tx.lock().ok()
                 .and_then(|mut opt| opt.take()){
-                    error_tx.send(Err(anyhow!("Error Loading Image: {:#?}", err)));
+                    let _ = error_tx.send(Err(anyhow!("Error Loading Image: {:#?}", err)));
                 }
         });
     
@@ -47,68 +51,157 @@ pub async fn load_image(source: &str) -> Result<HtmlImageElement> {
     Ok(image)
 }
 
+// ゲーム1つ分が使うページ上の要素。要素を分ければ1つのページにいくつでもゲームを置ける
+#[derive(Clone)]
+pub struct GameContext{
+    pub canvas: HtmlCanvasElement,
+    // DOMのメニューを出す場所。無ければメニューはcanvasに描く
+    pub ui_root: Option<Element>,
+    // キー入力を受け取る要素。普通はcanvas
+    pub input_target: HtmlElement,
+    // 保存するデータのキーに付ける名前
+    pub storage_namespace: String,
+}
+
+impl GameContext{
+    // input_idが無ければcanvasでキー入力を受け取り、storage_namespaceが無ければcanvas_idを使う
+    pub fn from_ids(
+        canvas_id: &str,
+        ui_id: &str,
+        input_id: Option<&str>,
+        storage_namespace: Option<&str>,
+    ) -> Result<Self>{
+        let canvas = browser::canvas(canvas_id)?;
+        let input_target = match input_id {
+            Some(id) => browser::find_html_element(id)?,
+            None => canvas.clone().into(),
+        };
+        Ok(GameContext{
+            canvas,
+            ui_root: browser::find_element(ui_id).ok(),
+            input_target,
+            storage_namespace: storage_namespace.unwrap_or(canvas_id).to_string(),
+        })
+    }
+
+    // メニューを閉じたあとキー入力がこのゲームに届くようにする
+    pub fn focus(&self) -> Result<()>{
+        self.input_target
+            .focus()
+            .map_err(|err| anyhow!("Could not set focus to the input target {:#?}", err))
+    }
+}
+
 #[async_trait::async_trait(?Send)]
 pub trait Game{
-    async fn initialize(&self) -> Result<Box<dyn Game>>;
+    async fn initialize(&self, context: &GameContext) -> Result<Box<dyn Game>>;
     fn update(&mut self, keystate: &KeyState);
     fn draw(&self, renderer: &Renderer);
+    // ブラウザの既定の動作(スクロールなど)を止めるキー
+    fn captured_keys(&self) -> Vec<&str>{
+        Vec::new()
+    }
 }
 
 const FRAME_SIZE: f32 = 1.0 / 60.0 * 1000.0;
 pub struct GameLoop{
     last_frame: f64,
     accumulated_delta: f32,
+    frame_rate: FrameRate,
 }
 type SharedLoopClosure = Rc<RefCell<Option<LoopClosure>>>;
+
+// 動いているゲームループ。捨てると次のフレームを取り消し、ループのクロージャとその持ち物を解放する
+pub struct GameLoopHandle{
+    closure: SharedLoopClosure,
+    frame: Rc<std::cell::Cell<Option<i32>>>,
+}
+
+impl Drop for GameLoopHandle{
+    fn drop(&mut self){
+        if let Some(frame) = self.frame.take() {
+            if let Err(err) = browser::cancel_animation_frame(frame) {
+                log!("Could not cancel animation frame {:#?}", err);
+            }
+        }
+        self.closure.borrow_mut().take();
+    }
+}
+
 impl GameLoop{
-    pub async fn start(game: impl Game + 'static) -> Result<()>{
-        let mut keyevent_receiver = prepare_input()?;
-        let mut game = game.initialize().await?;
+    // キー入力はcontext.input_targetにフォーカスがあるときだけ受け取る
+    pub async fn start(game: impl Game + 'static, context: GameContext) -> Result<GameLoopHandle>{
+        // ゲームループのクロージャが持つので、リスナーはループと同じだけ生きる
+        let mut key_input = prepare_input(&context.input_target)?;
+        let mut game = game.initialize(&context).await?;
         let mut game_loop = GameLoop{
             last_frame: browser::now()?,
             accumulated_delta: 0.0,
+            frame_rate: FrameRate::default(),
         };
 
-        let renderer = Renderer{
-            context: browser::context()?,
-        };
+        let renderer = Renderer::new(browser::context_2d(&context.canvas)?)?;
 
         let f: SharedLoopClosure = Rc::new(RefCell::new(None));
-        let g = f.clone();
+        // クロージャが自分自身を強く持つと循環して止められないので、弱い参照で次のフレームを頼む
+        let g = Rc::downgrade(&f);
+        let frame: Rc<std::cell::Cell<Option<i32>>> = Rc::default();
+        let next_frame = Rc::clone(&frame);
 
         let mut keystate = KeyState::new();
-        *g.borrow_mut() = Some(browser::create_ref_closure(move |perf:f64|{
-            process_input(&mut keystate, &mut keyevent_receiver);
+        *f.borrow_mut() = Some(browser::create_ref_closure(move |perf:f64|{
+            process_input(&mut keystate, &mut key_input);
+            // ゲームパッドはページ全体で共有なので、フォーカスのあるゲームだけが読む
+            if browser::has_focus(&context.input_target) {
+                process_gamepads(&mut keystate);
+            }
 
             let frame_time = perf - game_loop.last_frame;
             game_loop.accumulated_delta += frame_time as f32;
             //game_loop.accumulated_delta += (perf - game_loop.last_frame) as f32;
             while game_loop.accumulated_delta >= FRAME_SIZE{
                 game.update(&keystate);
+                keystate.end_update();
                 game_loop.accumulated_delta -= FRAME_SIZE;
             }
+            key_input.capture(&game.captured_keys());
             game_loop.last_frame = perf;
             game.draw(&renderer);
 
-            if cfg!(debug_assertions){
-                unsafe {
-                    draw_frame_rate(&renderer, frame_time);
-                }
+            game_loop.frame_rate.count(frame_time);
+            if renderer.settings().show_fps {
+                game_loop.frame_rate.draw(&renderer);
             }
 
-            browser::request_animation_frame(f.borrow().as_ref().unwrap());
+            let Some(f) = g.upgrade() else {
+                return;
+            };
+            let requested = match f.borrow().as_ref() {
+                Some(closure) => browser::request_animation_frame(closure),
+                None => return,
+            };
+            match requested {
+                Ok(id) => next_frame.set(Some(id)),
+                Err(err) => {
+                    log!("Could not request animation frame {:#?}", err);
+                }
+            }
         }));
 
-        browser::request_animation_frame(
-            g.borrow()
+        let first_frame = browser::request_animation_frame(
+            f.borrow()
                 .as_ref()
                 .ok_or_else(|| anyhow!("Game Loop: Loop is None"))?,
         )?;
-        Ok(())
+        frame.set(Some(first_frame));
+        Ok(GameLoopHandle{
+            closure: f,
+            frame,
+        })
     }
 }
 
-#[derive(Default)]
+#[derive(Default, Clone, Copy)]
 pub struct Rect{
     pub position: Point,
     // pub x: i16,
@@ -141,6 +234,10 @@ impl Rect{
         self.y() < rect.bottom() && self.bottom() > rect.y()
     }
 
+    pub fn contains(&self, point: &Point) -> bool{
+        (self.x()..self.right()).contains(&point.x) && (self.y()..self.bottom()).contains(&point.y)
+    }
+
     pub fn right(&self) -> i16{
         self.x() + self.width
     }
@@ -160,11 +257,154 @@ pub struct Point{
     pub y: i16,
 }
 
+const DEBUG_COLOR: &str = "#FF0000";
+
+// 図形の描画
+pub enum Shape<'a>{
+    Rect(&'a Rect),
+    RoundedRect(&'a Rect, i16),
+    Circle(Point, i16),
+    Line(Point, Point),
+    Path(&'a [Point], bool),
+}
+
+// 色はCSSの書式。定数は&'static strのまま、実行中に作った色はStringで持てる
+#[derive(Debug, Clone)]
+pub struct ShapeStyle{
+    pub fill: Option<Cow<'static, str>>,
+    pub stroke: Option<Cow<'static, str>>,
+    pub line_width: f32,
+    pub alpha: f32,
+}
+
+impl ShapeStyle{
+    pub const fn fill(color: &'static str) -> Self{
+        ShapeStyle{fill: Some(Cow::Borrowed(color)), stroke: None, line_width: 1.0, alpha: 1.0}
+    }
+
+    pub const fn stroke(color: &'static str, line_width: f32) -> Self{
+        ShapeStyle{fill: None, stroke: Some(Cow::Borrowed(color)), line_width, alpha: 1.0}
+    }
+
+    #[allow(dead_code)]
+    pub fn with_stroke(mut self, color: impl Into<Cow<'static, str>>, line_width: f32) -> Self{
+        self.stroke = Some(color.into());
+        self.line_width = line_width;
+        self
+    }
+
+    pub const fn with_alpha(mut self, alpha: f32) -> Self{
+        self.alpha = alpha;
+        self
+    }
+}
+
+// 角の半径は短い辺の半分まで
+fn corner_radius(rect: &Rect, radius: i16) -> i16{
+    radius.min(rect.width / 2).min(rect.height / 2).max(0)
+}
+
 pub struct Renderer{
     context: CanvasRenderingContext2d,
+    // 色付け(tint)用の作業キャンバス
+    scratch: CanvasRenderingContext2d,
+    settings: std::cell::Cell<RenderSettings>,
+}
+
+// 設定画面から変えられる描画の設定
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RenderSettings{
+    pub show_fps: bool,
+    // 点滅や回転などの動きを控える
+    pub reduced_motion: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Tint{
+    pub color: &'static str,
+    pub strength: f32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct DrawOptions{
+    pub flip_horizontal: bool,
+    pub flip_vertical: bool,
+    // ラジアン、pivotを中心に回転する
+    pub rotation: f32,
+    // destinationの左上からの相対位置。Noneなら中心
+    pub pivot: Option<Point>,
+    pub scale_x: f32,
+    pub scale_y: f32,
+    pub alpha: f32,
+    pub tint: Option<Tint>,
+}
+
+impl Default for DrawOptions{
+    fn default() -> Self{
+        DrawOptions{
+            flip_horizontal: false,
+            flip_vertical: false,
+            rotation: 0.0,
+            pivot: None,
+            scale_x: 1.0,
+            scale_y: 1.0,
+            alpha: 1.0,
+            tint: None,
+        }
+    }
+}
+
+// 描画先のpivotへ原点を移し、反転は負の拡大率にする
+struct ImageTransform{
+    origin: Point,
+    scale_x: f64,
+    scale_y: f64,
+    // 移した原点から見た描画先
+    local_destination: Rect,
+}
+
+impl DrawOptions{
+    fn transform(&self, destination: &Rect) -> ImageTransform{
+        let pivot = self.pivot.unwrap_or(Point{
+            x: destination.width / 2,
+            y: destination.height / 2,
+        });
+        let flip = |flipped: bool| if flipped { -1.0 } else { 1.0 };
+
+        ImageTransform{
+            origin: Point{
+                x: destination.x() + pivot.x,
+                y: destination.y() + pivot.y,
+            },
+            scale_x: f64::from(self.scale_x) * flip(self.flip_horizontal),
+            scale_y: f64::from(self.scale_y) * flip(self.flip_vertical),
+            local_destination: Rect::new_from_x_y(
+                -pivot.x,
+                -pivot.y,
+                destination.width,
+                destination.height,
+            ),
+        }
+    }
 }
 
 impl Renderer{
+    pub fn new(context: CanvasRenderingContext2d) -> Result<Self>{
+        Ok(Renderer{
+            context,
+            scratch: browser::context_2d(&browser::create_canvas()?)?,
+            settings: std::cell::Cell::new(RenderSettings::default()),
+        })
+    }
+
+    pub fn settings(&self) -> RenderSettings{
+        self.settings.get()
+    }
+
+    pub fn apply_settings(&self, settings: RenderSettings){
+        self.settings.set(settings);
+    }
+
     pub fn clear(&self, rect: &Rect){
         self.context.clear_rect(
             rect.x().into(),
@@ -177,11 +417,88 @@ impl Renderer{
     pub fn draw_image(&self, image: &HtmlImageElement, frame: &Rect, destination: &Rect){
         self.context
             .draw_image_with_html_image_element_and_sw_and_sh_and_dx_and_dy_and_dw_and_dh(
-                &image,
+                image,
+                frame.x().into(),
+                frame.y().into(),
+                frame.width.into(),
+                frame.height.into(),
+                destination.x().into(),
+                destination.y().into(),
+                destination.width.into(),
+                destination.height.into(),
+            )
+            .expect("Drawing is throwing exceptions! Unrecoverable error.");
+    }
+
+    pub fn draw_image_with_options(
+        &self,
+        image: &HtmlImageElement,
+        frame: &Rect,
+        destination: &Rect,
+        options: &DrawOptions,
+    ){
+        let transform = options.transform(destination);
+
+        self.context.save();
+        self.context.set_global_alpha(options.alpha.into());
+        let transformed = self
+            .context
+            .translate(transform.origin.x.into(), transform.origin.y.into())
+            .and_then(|_| self.context.rotate(options.rotation.into()))
+            .and_then(|_| self.context.scale(transform.scale_x, transform.scale_y));
+        if let Err(err) = transformed {
+            log!("Could not transform context {:#?}", err);
+        }
+
+        let local_destination = transform.local_destination;
+        match options.tint {
+            Some(tint) => self.draw_tinted(image, frame, &local_destination, &tint),
+            None => self.draw_image(image, frame, &local_destination),
+        }
+        self.context.restore();
+    }
+
+    fn draw_tinted(&self, image: &HtmlImageElement, frame: &Rect, destination: &Rect, tint: &Tint){
+        let scratch = self.scratch.canvas().expect("Scratch context has no canvas");
+        if scratch.width() < frame.width as u32 {
+            scratch.set_width(frame.width as u32);
+        }
+        if scratch.height() < frame.height as u32 {
+            scratch.set_height(frame.height as u32);
+        }
+
+        let tinted_frame = Rect::new_from_x_y(0, 0, frame.width, frame.height);
+        self.scratch.save();
+        self.scratch.clear_rect(0.0, 0.0, scratch.width().into(), scratch.height().into());
+        self.scratch
+            .draw_image_with_html_image_element_and_sw_and_sh_and_dx_and_dy_and_dw_and_dh(
+                image,
                 frame.x().into(),
                 frame.y().into(),
                 frame.width.into(),
                 frame.height.into(),
+                0.0,
+                0.0,
+                frame.width.into(),
+                frame.height.into(),
+            )
+            .expect("Drawing is throwing exceptions! Unrecoverable error.");
+        // 不透明な部分だけを塗りつぶす
+        if let Err(err) = self.scratch.set_global_composite_operation("source-atop") {
+            log!("Could not set composite operation {:#?}", err);
+        }
+        self.scratch.set_global_alpha(tint.strength.into());
+        self.scratch.set_fill_style_str(tint.color);
+        self.scratch.fill_rect(0.0, 0.0, frame.width.into(), frame.height.into());
+        self.scratch.restore();
+
+        self.context
+            .draw_image_with_html_canvas_element_and_sw_and_sh_and_dx_and_dy_and_dw_and_dh(
+                &scratch,
+                tinted_frame.x().into(),
+                tinted_frame.y().into(),
+                tinted_frame.width.into(),
+                tinted_frame.height.into(),
                 destination.x().into(),
                 destination.y().into(),
                 destination.width.into(),
@@ -199,14 +516,84 @@ impl Renderer{
 
     // debug用、矩形を描画
     pub fn draw_rect(&self, rect: &Rect){
-        self.context.set_stroke_style(&JsValue::from_str("#FF0000"));
+        self.draw_debug_shape(&Shape::Rect(rect));
+    }
+
+    // 当たり判定などデバッグ用の線
+    pub fn draw_debug_shape(&self, shape: &Shape){
+        self.draw_shape(shape, &ShapeStyle::stroke(DEBUG_COLOR, 1.0));
+    }
+
+    pub fn draw_shape(&self, shape: &Shape, style: &ShapeStyle){
+        self.context.save();
+        self.context.set_global_alpha(style.alpha.into());
+        self.context.set_line_width(style.line_width.into());
         self.context.begin_path();
-        self.context.stroke_rect(
-            rect.x().into(),
-            rect.y().into(),
-            rect.width.into(),
-            rect.height.into(),
-        );
+        match shape {
+            Shape::Rect(rect) => {
+                self.context.rect(
+                    rect.x().into(),
+                    rect.y().into(),
+                    rect.width.into(),
+                    rect.height.into(),
+                );
+            }
+            Shape::RoundedRect(rect, radius) => self.rounded_rect_path(rect, *radius),
+            Shape::Circle(center, radius) => {
+                if let Err(err) = self.context.arc(
+                    center.x.into(),
+                    center.y.into(),
+                    (*radius).into(),
+                    0.0,
+                    std::f64::consts::TAU,
+                ) {
+                    log!("Could not draw arc {:#?}", err);
+                }
+            }
+            Shape::Line(from, to) => {
+                self.context.move_to(from.x.into(), from.y.into());
+                self.context.line_to(to.x.into(), to.y.into());
+            }
+            Shape::Path(points, closed) => {
+                if let Some((first, rest)) = points.split_first() {
+                    self.context.move_to(first.x.into(), first.y.into());
+                    rest.iter().for_each(|point| {
+                        self.context.line_to(point.x.into(), point.y.into());
+                    });
+                    if *closed {
+                        self.context.close_path();
+                    }
+                }
+            }
+        }
+        if let Some(fill) = &style.fill {
+            self.context.set_fill_style_str(fill);
+            self.context.fill();
+        }
+        if let Some(stroke) = &style.stroke {
+            self.context.set_stroke_style_str(stroke);
+            self.context.stroke();
+        }
+        self.context.restore();
+    }
+
+    fn rounded_rect_path(&self, rect: &Rect, radius: i16){
+        let radius = f64::from(corner_radius(rect, radius));
+        let (left, top) = (f64::from(rect.x()), f64::from(rect.y()));
+        let (right, bottom) = (f64::from(rect.right()), f64::from(rect.bottom()));
+        self.context.move_to(left + radius, top);
+        let corners = [
+            (right, top, right, bottom),
+            (right, bottom, left, bottom),
+            (left, bottom, left, top),
+            (left, top, right, top),
+        ];
+        for (x1, y1, x2, y2) in corners {
+            if let Err(err) = self.context.arc_to(x1, y1, x2, y2, radius) {
+                log!("Could not draw rounded corner {:#?}", err);
+            }
+        }
+        self.context.close_path();
     }
 
     #[allow(dead_code)]
@@ -220,25 +607,159 @@ impl Renderer{
 
 }
 
-unsafe fn draw_frame_rate(renderer: &Renderer, frame_rate: f64){
-    static mut FRAMES_COUNTED: i32 = 0;
-    static mut TOTAL_FRAME_TIME: f64 = 0.0;
-    static mut FRAME_RATE: i32 = 0;
+// 描画順の管理。layerが先、同じlayer内ではzの小さい順に描画する
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Layer{
+    Background,
+    World,
+    Actors,
+    Foreground,
+    Hud,
+}
+
+type DrawCommand<'a> = Box<dyn Fn(&Renderer) + 'a>;
+
+struct DrawItem<'a>{
+    layer: Layer,
+    z: i16,
+    command: DrawCommand<'a>,
+}
+
+#[derive(Default)]
+pub struct DrawQueue<'a>{
+    items: Vec<DrawItem<'a>>,
+}
+
+impl<'a> DrawQueue<'a>{
+    pub fn new() -> Self{
+        DrawQueue{items: vec![]}
+    }
+
+    pub fn submit(&mut self, layer: Layer, z: i16, command: impl Fn(&Renderer) + 'a){
+        self.items.push(DrawItem{layer, z, command: Box::new(command)});
+    }
+
+    fn sort(&mut self){
+        // 同じ(layer, z)なら登録順を保つ
+        self.items.sort_by_key(|item| (item.layer, item.z));
+    }
+
+    pub fn draw(mut self, renderer: &Renderer){
+        self.sort();
+        self.items.iter().for_each(|item| (item.command)(renderer));
+    }
+}
+
+// 1秒ごとに数え直すフレームレート。ゲームループごとに持つ
+#[derive(Default)]
+struct FrameRate{
+    frames_counted: i32,
+    total_frame_time: f64,
+    frame_rate: i32,
+}
+
+impl FrameRate{
+    fn count(&mut self, frame_time: f64){
+        self.frames_counted += 1;
+        self.total_frame_time += frame_time;
+
+        if self.total_frame_time > 1000.0 {
+            self.frame_rate = self.frames_counted;
+            self.total_frame_time = 0.0;
+            self.frames_counted = 0;
+        }
+    }
+
+    fn draw(&self, renderer: &Renderer){
+        if let Err(err) = renderer.draw_text(
+            &format!("Frame Rate: {}", self.frame_rate),
+            &Point{x: 400, y: 100},
+        ) {
+            log!("Could not draw text {:#?}", err);
+        }
+    }
+}
+
+// 視差スクロールする背景
+#[derive(Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ParallaxLayerConfig {
+    pub image: String,
+    pub scroll_factor: f32,
+    #[serde(default)]
+    pub offset_y: i16,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct ParallaxConfig {
+    pub layers: Vec<ParallaxLayerConfig>,
+}
+
+struct ParallaxLayer {
+    image: HtmlImageElement,
+    width: f32,
+    scroll_factor: f32,
+    offset_y: i16,
+    offset_x: f32,
+}
+
+pub struct Parallax {
+    layers: Vec<ParallaxLayer>,
+    viewport_width: i16,
+}
+
+impl Parallax {
+    pub async fn load(config: ParallaxConfig, viewport_width: i16) -> Result<Self> {
+        let mut layers = vec![];
+        for layer in config.layers {
+            let image = load_image(&layer.image).await?;
+            layers.push(ParallaxLayer {
+                width: image.width() as f32,
+                image,
+                scroll_factor: layer.scroll_factor,
+                offset_y: layer.offset_y,
+                offset_x: 0.0,
+            });
+        }
+        Ok(Parallax { layers, viewport_width })
+    }
 
-    FRAMES_COUNTED += 1;
-    TOTAL_FRAME_TIME += frame_rate;
+    pub fn scroll(&mut self, distance: i16) {
+        self.layers.iter_mut().for_each(|layer| {
+            layer.offset_x = wrap_offset(
+                layer.offset_x + f32::from(distance) * layer.scroll_factor,
+                layer.width,
+            );
+        });
+    }
 
-    if TOTAL_FRAME_TIME > 1000.0 {
-        FRAME_RATE = FRAMES_COUNTED;
-        TOTAL_FRAME_TIME = 0.0;
-        FRAMES_COUNTED = 0;
+    pub fn draw(&self, renderer: &Renderer) {
+        self.layers
+            .iter()
+            .filter(|layer| layer.width > 0.0)
+            .for_each(|layer| {
+                let mut x = layer.offset_x;
+                while x < f32::from(self.viewport_width) {
+                    renderer.draw_entire_image(
+                        &layer.image,
+                        &Point { x: x.round() as i16, y: layer.offset_y },
+                    );
+                    x += layer.width;
+                }
+            });
     }
+}
 
-    if let Err(err) = renderer.draw_text(
-        &format!("Frame Rate: {}", FRAME_RATE),
-        &Point{x: 400, y: 100},
-    ) {
-        log!("Could not draw text {:#?}", err);
+// 画像の幅で折り返し、常に(-width, 0]に収める
+fn wrap_offset(offset: f32, width: f32) -> f32 {
+    if width <= 0.0 {
+        return 0.0;
+    }
+    let wrapped = offset.rem_euclid(width);
+    if wrapped > 0.0 {
+        wrapped - width
+    } else {
+        wrapped
     }
 }
 
@@ -257,6 +778,10 @@ impl Image {
         Self { element, bounding_box }
     }
 
+    pub fn with_bounding_box(element: HtmlImageElement, bounding_box: Rect) -> Self {
+        Self { element, bounding_box }
+    }
+
     pub fn draw(&self, renderer: &Renderer){
         renderer.draw_entire_image(&self.element, &self.bounding_box.position);
     }
@@ -304,6 +829,25 @@ pub struct Sheet{
     pub frames: HashMap<String, Cell>,
 }
 
+// アニメーションごとの当たり判定。座標はトリミング前の画像の左上が原点
+// framesに書いたフレームはアニメーションの設定より優先する
+#[derive(Deserialize, Clone, Default)]
+pub struct Hitboxes{
+    #[serde(default)]
+    animations: HashMap<String, SheetRect>,
+    #[serde(default)]
+    frames: HashMap<String, SheetRect>,
+}
+
+impl Hitboxes {
+    pub fn get(&self, animation: &str, frame: &str) -> Option<Rect> {
+        self.frames
+            .get(frame)
+            .or_else(|| self.animations.get(animation))
+            .map(|hitbox| Rect::new_from_x_y(hitbox.x, hitbox.y, hitbox.w, hitbox.h))
+    }
+}
+
 pub struct SpriteSheet {
     sheet: Sheet,
     image: HtmlImageElement,
@@ -327,124 +871,928 @@ impl SpriteSheet {
     }
 }
 
+// ビットマップフォント
+#[derive(Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct FontMetrics {
+    pub line_height: i16,
+    #[serde(default)]
+    pub letter_spacing: i16,
+}
 
-// キーボード入力
-enum KeyPress{
-    KeyUp(web_sys::KeyboardEvent),
-    KeyDown(web_sys::KeyboardEvent),
+// Sheet形式: frameの名前がそのまま文字になる
+#[derive(Deserialize, Clone)]
+pub struct FontSheet {
+    pub frames: HashMap<String, Cell>,
+    pub font: FontMetrics,
 }
 
-pub struct KeyState{
-    pressed_keys: HashMap<String, web_sys::KeyboardEvent>,
+// BMFont形式(JSON出力)
+#[derive(Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct BmFontCommon {
+    pub line_height: i16,
 }
 
-impl KeyState{
-    fn new() -> Self{
-        KeyState { pressed_keys: HashMap::new() }
+#[derive(Deserialize, Clone)]
+pub struct BmFontChar {
+    pub id: u32,
+    pub x: i16,
+    pub y: i16,
+    pub width: i16,
+    pub height: i16,
+    pub xoffset: i16,
+    pub yoffset: i16,
+    pub xadvance: i16,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct BmFont {
+    pub common: BmFontCommon,
+    pub chars: Vec<BmFontChar>,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(untagged)]
+pub enum FontDescriptor {
+    Sheet(FontSheet),
+    BmFont(BmFont),
+}
+
+struct GlyphLayout {
+    advances: HashMap<char, i16>,
+    line_height: i16,
+}
+
+impl GlyphLayout {
+    fn glyph(&self, c: char) -> Option<char> {
+        if self.advances.contains_key(&c) {
+            Some(c)
+        } else {
+            let upper = c.to_ascii_uppercase();
+            self.advances.contains_key(&upper).then_some(upper)
+        }
     }
 
-    pub fn is_pressed(&self, code: &str) -> bool{
-        self.pressed_keys.contains_key(code)
+    fn space_advance(&self) -> i16 {
+        self.advances
+            .get(&' ')
+            .copied()
+            .unwrap_or(self.line_height / 2)
     }
 
-    fn set_pressed(&mut self, code: &str, event: web_sys::KeyboardEvent){
-        self.pressed_keys.insert(code.into(), event);
+    // 各文字のペン位置を返す。フォントに無い文字は空白として扱う
+    fn positions(&self, text: &str, origin: &Point) -> Vec<(char, Point)> {
+        let mut pen = *origin;
+        let mut positions = vec![];
+        for c in text.chars() {
+            if c == '\n' {
+                pen = Point { x: origin.x, y: pen.y + self.line_height };
+                continue;
+            }
+            match self.glyph(c) {
+                Some(glyph) => {
+                    positions.push((glyph, pen));
+                    pen.x += self.advances[&glyph];
+                }
+                None => pen.x += self.space_advance(),
+            }
+        }
+        positions
     }
 
-    fn set_released(&mut self, code: &str){
-        self.pressed_keys.remove(code.into());
+    fn measure(&self, text: &str) -> (i16, i16) {
+        let width = text
+            .split('\n')
+            .map(|line| {
+                line.chars()
+                    .map(|c| match self.glyph(c) {
+                        Some(glyph) => self.advances[&glyph],
+                        None => self.space_advance(),
+                    })
+                    .sum::<i16>()
+            })
+            .max()
+            .unwrap_or(0);
+        let lines = text.split('\n').count() as i16;
+        (width, lines * self.line_height)
     }
 }
 
-fn process_input(state: &mut KeyState, keyevent_receiver: &mut UnboundedReceiver<KeyPress>){
-    loop{
-        match keyevent_receiver.try_next(){
-            Ok(None) => break,
-            Err(_) => break,
-            Ok(Some(evt)) => match evt {
-                KeyPress::KeyUp(evt) => state.set_released(&evt.code()),
-                KeyPress::KeyDown(evt) => state.set_pressed(&evt.code(), evt),
+pub struct BitmapFont {
+    sprite_sheet: SpriteSheet,
+    layout: GlyphLayout,
+}
+
+impl BitmapFont {
+    pub fn new(descriptor: FontDescriptor, image: HtmlImageElement) -> Self {
+        let (frames, advances, line_height) = match descriptor {
+            FontDescriptor::Sheet(sheet) => {
+                let advances = sheet
+                    .frames
+                    .iter()
+                    .filter_map(|(name, cell)| {
+                        let mut chars = name.chars();
+                        match (chars.next(), chars.next()) {
+                            (Some(c), None) => Some((
+                                c,
+                                cell.sprite_source_size.x
+                                    + cell.sprite_source_size.w
+                                    + sheet.font.letter_spacing,
+                            )),
+                            _ => None,
+                        }
+                    })
+                    .collect();
+                (sheet.frames, advances, sheet.font.line_height)
             }
+            FontDescriptor::BmFont(font) => {
+                let glyphs: Vec<(char, &BmFontChar)> = font
+                    .chars
+                    .iter()
+                    .filter_map(|glyph| char::from_u32(glyph.id).map(|c| (c, glyph)))
+                    .collect();
+                let frames = glyphs
+                    .iter()
+                    .map(|(c, glyph)| {
+                        (
+                            c.to_string(),
+                            Cell {
+                                frame: SheetRect {
+                                    x: glyph.x,
+                                    y: glyph.y,
+                                    w: glyph.width,
+                                    h: glyph.height,
+                                },
+                                sprite_source_size: SheetRect {
+                                    x: glyph.xoffset,
+                                    y: glyph.yoffset,
+                                    w: glyph.width,
+                                    h: glyph.height,
+                                },
+                            },
+                        )
+                    })
+                    .collect();
+                let advances = glyphs
+                    .iter()
+                    .map(|(c, glyph)| (*c, glyph.xadvance))
+                    .collect();
+                (frames, advances, font.common.line_height)
+            }
+        };
+
+        BitmapFont {
+            sprite_sheet: SpriteSheet::new(Sheet { frames }, image),
+            layout: GlyphLayout { advances, line_height },
         }
     }
 
-}
+    pub fn draw_text(&self, renderer: &Renderer, text: &str, position: &Point) {
+        for (glyph, pen) in self.layout.positions(text, position) {
+            if let Some(cell) = self.sprite_sheet.cell(&glyph.to_string()) {
+                self.sprite_sheet.draw(
+                    renderer,
+                    &Rect::new_from_x_y(cell.frame.x, cell.frame.y, cell.frame.w, cell.frame.h),
+                    &Rect::new_from_x_y(
+                        pen.x + cell.sprite_source_size.x,
+                        pen.y + cell.sprite_source_size.y,
+                        cell.frame.w,
+                        cell.frame.h,
+                    ),
+                );
+            }
+        }
+    }
 
-fn prepare_input() -> Result<UnboundedReceiver<KeyPress>>{
-    let (keydown_sender, keyevent_receiver) = unbounded();
-    let keydown_sender = Rc::new(RefCell::new(keydown_sender));
-    let keyup_sender = Rc::clone(&keydown_sender);
-    
-    let onkeydown = browser::closure_wrap(
-        Box::new(move |keycode: web_sys::KeyboardEvent|{
-            keydown_sender
-                .borrow_mut()
-                .start_send(KeyPress::KeyDown(keycode));
-        })
-        as Box<dyn FnMut(web_sys::KeyboardEvent)>
-    );
-
-    let onkeyup = browser::closure_wrap(
-        Box::new(move |keycode: web_sys::KeyboardEvent|{
-            keyup_sender
-                .borrow_mut()
-                .start_send(KeyPress::KeyUp(keycode));
-        })
-        as Box<dyn FnMut(web_sys::KeyboardEvent)>
-    );
+    pub fn measure(&self, text: &str) -> (i16, i16) {
+        self.layout.measure(text)
+    }
+}
 
-    browser::window()?.set_onkeydown(Some(onkeydown.as_ref().unchecked_ref()));
-    browser::window()?.set_onkeyup(Some(onkeyup.as_ref().unchecked_ref()));
-    onkeydown.forget();
-    onkeyup.forget();
 
-    Ok(keyevent_receiver)
+// 動く足場などの移動経路
+#[derive(Debug, Clone, Copy)]
+pub enum Easing {
+    #[allow(dead_code)]
+    Linear,
+    EaseInOut,
 }
 
-// サウンド
+impl Easing {
+    fn apply(&self, t: f32) -> f32 {
+        match self {
+            Easing::Linear => t,
+            Easing::EaseInOut => t * t * (3.0 - 2.0 * t),
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
-pub struct Audio{
-    context: AudioContext,
+pub enum MotionPath {
+    // 原点を中心にamplitudeの幅で往復する
+    Oscillate { amplitude: Point, period: u16 },
+    // 原点からの相対位置を順にたどり、最後の点から最初の点へ戻る
+    Waypoints { points: Vec<Point>, frames_per_leg: u16, easing: Easing },
 }
 
 #[derive(Debug, Clone)]
-pub struct Sound{
-    buffer: AudioBuffer,
+pub struct Motion {
+    path: MotionPath,
+    frame: u32,
+    offset: Point,
 }
 
-impl Audio{
-    pub fn new() -> Result<Self>{
-        Ok(Audio {
-            context: sound::create_audio_context()?,
-        })
-    }
-
-    pub async fn load_sound(&self, filename: &str) -> Result<Sound> {
-        let array_buffer = browser::fetch_array_buffer(filename).await?;
-        let audio_buffer = sound::decode_audio_data(&self.context, &array_buffer).await?;
-        
-        Ok(Sound{buffer: audio_buffer,})
+impl Motion {
+    pub fn new(path: MotionPath) -> Self {
+        let mut motion = Motion {
+            path,
+            frame: 0,
+            offset: Point::default(),
+        };
+        motion.offset = motion.offset_at(0);
+        motion
     }
 
-    pub fn play_sound(&self, sound: &Sound) -> Result<()>{
-        sound::play_sound(&self.context, &sound.buffer, sound::LOOPING::NO)
-    }
+    fn offset_at(&self, frame: u32) -> Point {
+        match &self.path {
+            MotionPath::Oscillate { amplitude, period } => {
+                let period = u32::from((*period).max(1));
+                let phase = (frame % period) as f32 / period as f32 * std::f32::consts::TAU;
+                Point {
+                    x: (f32::from(amplitude.x) * phase.sin()).round() as i16,
+                    y: (f32::from(amplitude.y) * phase.sin()).round() as i16,
+                }
+            }
+            MotionPath::Waypoints { points, frames_per_leg, easing } => {
+                if points.len() < 2 {
+                    return points.first().copied().unwrap_or_default();
+                }
+                let frames_per_leg = u32::from((*frames_per_leg).max(1));
+                let leg = (frame / frames_per_leg) as usize % points.len();
+                let t = easing.apply((frame % frames_per_leg) as f32 / frames_per_leg as f32);
+                let (from, to) = (points[leg], points[(leg + 1) % points.len()]);
+                Point {
+                    x: from.x + (f32::from(to.x - from.x) * t).round() as i16,
+                    y: from.y + (f32::from(to.y - from.y) * t).round() as i16,
+                }
+            }
+        }
+    }
+
+    // 始めの位置から今の位置までのずれ
+    pub fn offset(&self) -> Point {
+        self.offset
+    }
+
+    // 道すじの頂点(始めの位置からの相対位置)と、最後の点から最初の点へ戻るかどうか
+    pub fn trail(&self) -> (Vec<Point>, bool) {
+        match &self.path {
+            MotionPath::Oscillate { amplitude, .. } => (
+                vec![Point { x: -amplitude.x, y: -amplitude.y }, *amplitude],
+                false,
+            ),
+            MotionPath::Waypoints { points, .. } => (points.clone(), true),
+        }
+    }
+
+    // 1フレーム進めて、その間の移動量を返す
+    pub fn advance(&mut self) -> Point {
+        self.frame = self.frame.wrapping_add(1);
+        let next = self.offset_at(self.frame);
+        let delta = Point {
+            x: next.x - self.offset.x,
+            y: next.y - self.offset.y,
+        };
+        self.offset = next;
+        delta
+    }
+}
+
+// タイルマップ
+#[derive(Deserialize, Clone)]
+pub struct TileDefinition {
+    pub sprite: String,
+    #[serde(default)]
+    pub solid: bool,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct TilesetConfig {
+    pub tile_width: i16,
+    pub tile_height: i16,
+    pub tiles: HashMap<u16, TileDefinition>,
+}
+
+pub struct Tileset {
+    sheet: Rc<SpriteSheet>,
+    config: TilesetConfig,
+}
+
+impl Tileset {
+    pub const EMPTY: u16 = 0;
+
+    pub fn new(sheet: Rc<SpriteSheet>, config: TilesetConfig) -> Self {
+        Tileset { sheet, config }
+    }
+
+    pub fn tile_height(&self) -> i16 {
+        self.config.tile_height
+    }
+}
+
+impl TilesetConfig {
+    fn tile(&self, id: u16) -> Option<&TileDefinition> {
+        self.tiles.get(&id)
+    }
+
+    // タイルIDからシート上のフレームを引く
+    fn frame(&self, sheet: &Sheet, id: u16) -> Option<Rect> {
+        self.tile(id)
+            .and_then(|tile| sheet.frames.get(&tile.sprite))
+            .map(|cell| Rect::new_from_x_y(cell.frame.x, cell.frame.y, cell.frame.w, cell.frame.h))
+    }
+}
+
+pub struct Tilemap {
+    tileset: Rc<Tileset>,
+    grid: TileGrid,
+}
+
+impl Tilemap {
+    // tilesは左上から行ごとに並べたタイルID。0は空白
+    pub fn new(tileset: Rc<Tileset>, columns: usize, tiles: Vec<u16>, position: Point) -> Self {
+        Tilemap {
+            tileset,
+            grid: TileGrid::new(columns, tiles, position),
+        }
+    }
+
+    // flipsはtilesと同じ並び。足りない分は反転しない
+    pub fn with_flips(mut self, flips: Vec<TileFlip>) -> Self {
+        self.grid.flips = flips;
+        self
+    }
+
+    // 当たり判定のあるタイルの矩形(上の行から順に並ぶ)
+    pub fn solid_boxes(&self) -> Vec<Rect> {
+        self.grid.solid_boxes(&self.tileset.config)
+    }
+
+    pub fn draw(&self, renderer: &Renderer) {
+        let config = &self.tileset.config;
+        self.grid.cells(config).for_each(|(column, row, id)| {
+            if let Some(frame) = config.frame(&self.tileset.sheet.sheet, id) {
+                let destination = self.grid.tile_box(config, column, row);
+                let destination = Rect::new(destination.position, frame.width, frame.height);
+                let flip = self.grid.flip_at(column, row);
+                if flip == TileFlip::default() {
+                    self.tileset.sheet.draw(renderer, &frame, &destination);
+                } else {
+                    renderer.draw_image_with_options(
+                        self.tileset.sheet.image(),
+                        &frame,
+                        &destination,
+                        &DrawOptions {
+                            flip_horizontal: flip.horizontal,
+                            flip_vertical: flip.vertical,
+                            ..DrawOptions::default()
+                        },
+                    );
+                }
+            }
+        });
+    }
+
+    pub fn move_horizontally(&mut self, distance: i16) {
+        self.grid.position.x += distance;
+    }
+
+    pub fn right(&self) -> i16 {
+        self.grid.right(&self.tileset.config)
+    }
+}
+
+// Tiledで反転して置いたタイル
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct TileFlip {
+    pub horizontal: bool,
+    pub vertical: bool,
+}
+
+// タイルIDの並びと位置。シートの画像が無くても配置と当たり判定を計算できる
+struct TileGrid {
+    columns: usize,
+    tiles: Vec<u16>,
+    flips: Vec<TileFlip>,
+    position: Point,
+}
+
+impl TileGrid {
+    fn new(columns: usize, tiles: Vec<u16>, position: Point) -> Self {
+        TileGrid {
+            columns: columns.max(1),
+            tiles,
+            flips: Vec::new(),
+            position,
+        }
+    }
+
+    fn flip_at(&self, column: usize, row: usize) -> TileFlip {
+        self.flips.get(row * self.columns + column).copied().unwrap_or_default()
+    }
+
+    fn rows(&self) -> usize {
+        self.tiles.len().div_ceil(self.columns)
+    }
+
+    // 空白と定義の無いIDはNone
+    fn id_at(&self, config: &TilesetConfig, column: usize, row: usize) -> Option<u16> {
+        if column >= self.columns {
+            return None;
+        }
+        self.tiles
+            .get(row * self.columns + column)
+            .copied()
+            .filter(|id| *id != Tileset::EMPTY && config.tile(*id).is_some())
+    }
+
+    fn tile_box(&self, config: &TilesetConfig, column: usize, row: usize) -> Rect {
+        Rect::new_from_x_y(
+            self.position.x + column as i16 * config.tile_width,
+            self.position.y + row as i16 * config.tile_height,
+            config.tile_width,
+            config.tile_height,
+        )
+    }
+
+    fn cells<'a>(&'a self, config: &'a TilesetConfig) -> impl Iterator<Item = (usize, usize, u16)> + 'a {
+        (0..self.rows()).flat_map(move |row| {
+            (0..self.columns)
+                .filter_map(move |column| self.id_at(config, column, row).map(|id| (column, row, id)))
+        })
+    }
+
+    fn solid_boxes(&self, config: &TilesetConfig) -> Vec<Rect> {
+        self.cells(config)
+            .filter(|(_, _, id)| config.tile(*id).is_some_and(|tile| tile.solid))
+            .map(|(column, row, _)| self.tile_box(config, column, row))
+            .collect()
+    }
+
+    fn right(&self, config: &TilesetConfig) -> i16 {
+        self.position.x + self.columns as i16 * config.tile_width
+    }
+}
+
+// キーボード入力
+enum KeyPress{
+    KeyUp(web_sys::KeyboardEvent),
+    KeyDown(web_sys::KeyboardEvent),
+    // フォーカスが外れるとkeyupが届かないので、押しっぱなしのキーをすべて離す
+    Blur,
+}
+
+pub struct KeyState{
+    pressed_keys: HashSet<String>,
+    // 前回のupdateから新しく押されたキー。押された順に並ぶ
+    just_pressed: Vec<String>,
+}
+
+impl KeyState{
+    fn new() -> Self{
+        KeyState {
+            pressed_keys: HashSet::new(),
+            just_pressed: Vec::new(),
+        }
+    }
+
+    pub fn is_pressed(&self, code: &str) -> bool{
+        self.pressed_keys.contains(code)
+    }
+
+    // 押しっぱなしでは反応せず、押した瞬間だけtrueになる
+    pub fn was_pressed(&self, code: &str) -> bool{
+        self.just_pressed.iter().any(|pressed| pressed == code)
+    }
+
+    // この更新で最初に押されたキー。キーの割り当てを変えるときに使う
+    pub fn first_pressed(&self) -> Option<&str>{
+        self.just_pressed.first().map(|code| code.as_str())
+    }
+
+    fn set_pressed(&mut self, code: &str){
+        if self.pressed_keys.insert(code.into()) {
+            self.just_pressed.push(code.into());
+        }
+    }
+
+    fn end_update(&mut self){
+        self.just_pressed.clear();
+    }
+
+    fn set_released(&mut self, code: &str){
+        self.pressed_keys.remove(code);
+    }
+
+    fn release_all(&mut self){
+        self.pressed_keys.clear();
+    }
+}
+
+fn process_input(state: &mut KeyState, key_input: &mut KeyInput){
+    loop{
+        match key_input.receiver.try_next(){
+            Ok(None) => break,
+            Err(_) => break,
+            Ok(Some(evt)) => match evt {
+                KeyPress::KeyUp(evt) => state.set_released(&evt.code()),
+                KeyPress::KeyDown(evt) => state.set_pressed(&evt.code()),
+                KeyPress::Blur => state.release_all(),
+            }
+        }
+    }
+
+}
+
+// ゲームパッド(標準配置)のボタンもキーと同じように扱う
+pub const GAMEPAD_A: &str = "GamepadA";
+pub const GAMEPAD_B: &str = "GamepadB";
+pub const GAMEPAD_UP: &str = "GamepadUp";
+pub const GAMEPAD_DOWN: &str = "GamepadDown";
+const GAMEPAD_BUTTONS: [(u32, &str); 4] = [
+    (0, GAMEPAD_A),
+    (1, GAMEPAD_B),
+    (12, GAMEPAD_UP),
+    (13, GAMEPAD_DOWN),
+];
+
+fn process_gamepads(state: &mut KeyState){
+    let gamepads = match browser::window().and_then(|window| {
+        window
+            .navigator()
+            .get_gamepads()
+            .map_err(|err| anyhow!("Could not get gamepads {:#?}", err))
+    }) {
+        Ok(gamepads) => gamepads,
+        Err(_) => return,
+    };
+
+    let pressed: HashSet<&str> = gamepads
+        .iter()
+        .filter_map(|gamepad| gamepad.dyn_into::<Gamepad>().ok())
+        .flat_map(|gamepad| {
+            let buttons = gamepad.buttons();
+            GAMEPAD_BUTTONS
+                .iter()
+                .filter(move |(index, _code)| {
+                    buttons
+                        .get(*index)
+                        .dyn_into::<GamepadButton>()
+                        .map(|button| button.pressed())
+                        .unwrap_or(false)
+                })
+                .map(|(_index, code)| *code)
+        })
+        .collect();
+
+    GAMEPAD_BUTTONS.iter().for_each(|(_index, code)| {
+        if pressed.contains(code) {
+            state.set_pressed(code);
+        } else {
+            state.set_released(code);
+        }
+    });
+}
+
+// キー入力のリスナーと、そこから届くイベント。捨てるとリスナーも外れる
+struct KeyInput{
+    receiver: UnboundedReceiver<KeyPress>,
+    captured: Rc<RefCell<HashSet<String>>>,
+    _listeners: Listeners,
+}
+
+impl KeyInput{
+    fn capture(&self, keys: &[&str]){
+        let mut captured = self.captured.borrow_mut();
+        if captured.len() != keys.len() || keys.iter().any(|key| !captured.contains(*key)) {
+            *captured = keys.iter().map(|key| key.to_string()).collect();
+        }
+    }
+}
+
+fn prepare_input(target: &HtmlElement) -> Result<KeyInput>{
+    let mut listeners = Listeners::default();
+    let (keydown_sender, keyevent_receiver) = unbounded();
+    let keyup_sender = keydown_sender.clone();
+    let blur_sender = keydown_sender.clone();
+    let captured: Rc<RefCell<HashSet<String>>> = Rc::new(RefCell::new(HashSet::new()));
+    let keydown_captured = Rc::clone(&captured);
+    let keyup_captured = Rc::clone(&captured);
+
+    // ゲームで使うキーだけページのスクロールなどを止める
+    listeners.add(target, "keydown", move |keycode: web_sys::KeyboardEvent|{
+        if keydown_captured.borrow().contains(&keycode.code()) {
+            keycode.prevent_default();
+        }
+        let _ = keydown_sender.unbounded_send(KeyPress::KeyDown(keycode));
+    })?;
+    listeners.add(target, "keyup", move |keycode: web_sys::KeyboardEvent|{
+        if keyup_captured.borrow().contains(&keycode.code()) {
+            keycode.prevent_default();
+        }
+        let _ = keyup_sender.unbounded_send(KeyPress::KeyUp(keycode));
+    })?;
+    listeners.add(target, "blur", move |_: web_sys::Event|{
+        let _ = blur_sender.unbounded_send(KeyPress::Blur);
+    })?;
+
+    Ok(KeyInput{
+        receiver: keyevent_receiver,
+        captured,
+        _listeners: listeners,
+    })
+}
+
+// サウンド
+
+#[derive(Debug, Clone)]
+pub struct Audio{
+    context: AudioContext,
+    music: GainNode,
+    sfx: GainNode,
+}
+
+#[derive(Debug, Clone)]
+pub struct Sound{
+    buffer: AudioBuffer,
+}
+
+impl Audio{
+    pub fn new() -> Result<Self>{
+        let context = sound::create_audio_context()?;
+        Ok(Audio {
+            music: sound::create_channel(&context)?,
+            sfx: sound::create_channel(&context)?,
+            context,
+        })
+    }
+
+    pub async fn load_sound(&self, filename: &str) -> Result<Sound> {
+        let array_buffer = browser::fetch_array_buffer(filename).await?;
+        let audio_buffer = sound::decode_audio_data(&self.context, &array_buffer).await?;
+        
+        Ok(Sound{buffer: audio_buffer,})
+    }
+
+    pub fn play_sound(&self, sound: &Sound) -> Result<()>{
+        sound::play_sound(&self.context, &sound.buffer, sound::Looping::No, &self.sfx)
+    }
 
     pub fn play_looping_sound(&self, sound: &Sound) -> Result<()>{
-        sound::play_sound(&self.context, &sound.buffer, sound::LOOPING::YES)
+        sound::play_sound(&self.context, &sound.buffer, sound::Looping::Yes, &self.music)
+    }
+
+    // 音量は0.0から1.0
+    pub fn set_music_volume(&self, volume: f32){
+        sound::set_volume(&self.music, volume);
+    }
+
+    pub fn set_sfx_volume(&self, volume: f32){
+        sound::set_volume(&self.sfx, volume);
+    }
+
+    // 鳴っている音をすべてその場で止める
+    pub fn suspend(&self) -> Result<()>{
+        self.context
+            .suspend()
+            .map(|_promise| ())
+            .map_err(|err| anyhow!("Could not suspend audio {:#?}", err))
+    }
+
+    pub fn resume(&self) -> Result<()>{
+        self.context
+            .resume()
+            .map(|_promise| ())
+            .map_err(|err| anyhow!("Could not resume audio {:#?}", err))
+    }
+}
+
+// 即時モードのUI。updateの中で毎フレームウィジェットを呼ぶと、押されたかどうかがその場で返る。
+// 呼ばれたウィジェットは覚えておいてdrawでcanvasに描くので、#uiのDOMには頼らない
+const GUI_PREVIOUS_KEYS: [&str; 2] = ["ArrowUp", GAMEPAD_UP];
+const GUI_NEXT_KEYS: [&str; 2] = ["ArrowDown", GAMEPAD_DOWN];
+const GUI_CONFIRM_KEYS: [&str; 3] = ["Enter", "Space", GAMEPAD_A];
+const GUI_SLIDER_STEP: f32 = 0.1;
+const GUI_PADDING: i16 = 10;
+const GUI_TRACK_HEIGHT: i16 = 8;
+const GUI_STYLE: ShapeStyle = ShapeStyle::fill("#000000").with_alpha(0.5);
+const GUI_FOCUSED_STYLE: ShapeStyle = ShapeStyle::fill("#C0392B").with_alpha(0.8);
+const GUI_TRACK_STYLE: ShapeStyle = ShapeStyle::fill("#FFFFFF").with_alpha(0.3);
+const GUI_FILL_STYLE: ShapeStyle = ShapeStyle::fill("#FFFFFF");
+
+// 1フレーム分の入力
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GuiInput{
+    pub pointer: Option<Point>,
+    pub click: Option<Point>,
+    pub previous: bool,
+    pub next: bool,
+    pub decrease: bool,
+    pub increase: bool,
+    pub confirm: bool,
+}
+
+impl GuiInput{
+    pub fn new(keystate: &KeyState, pointer_events: &[PointerEvent]) -> Self{
+        let any = |keys: &[&str]| keys.iter().any(|key| keystate.was_pressed(key));
+        let mut input = GuiInput{
+            previous: any(&GUI_PREVIOUS_KEYS),
+            next: any(&GUI_NEXT_KEYS),
+            decrease: keystate.was_pressed("ArrowLeft"),
+            increase: keystate.was_pressed("ArrowRight"),
+            confirm: any(&GUI_CONFIRM_KEYS),
+            ..GuiInput::default()
+        };
+        pointer_events.iter().for_each(|event| match event {
+            PointerEvent::Move(point) => input.pointer = Some(*point),
+            PointerEvent::Click(point) => input.click = Some(*point),
+        });
+        input
+    }
+}
+
+enum Widget{
+    Button{rect: Rect, label: String},
+    Toggle{rect: Rect, label: String, on: bool},
+    Slider{rect: Rect, label: String, value: f32},
+    Text{position: Point, text: String},
+}
+
+#[derive(Default)]
+pub struct Gui{
+    input: GuiInput,
+    focus: usize,
+    // このフレームで呼ばれたフォーカスできるウィジェットの数
+    count: usize,
+    // フォーカスの順番(Textはフォーカスできないので無し)と描くもの
+    widgets: Vec<(Option<usize>, Widget)>,
+}
+
+impl Gui{
+    pub fn begin(&mut self, input: GuiInput){
+        // 前のフレームのウィジェットの数で上下に回す
+        if self.count > 0 {
+            if input.previous {
+                self.focus = (self.focus + self.count - 1) % self.count;
+            }
+            if input.next {
+                self.focus = (self.focus + 1) % self.count;
+            }
+        }
+        self.input = input;
+        self.count = 0;
+        self.widgets.clear();
+    }
+
+    pub fn button(&mut self, rect: Rect, label: &str) -> bool{
+        let (index, focused, clicked) = self.focusable(&rect);
+        self.widgets.push((Some(index), Widget::Button{rect, label: label.to_string()}));
+        clicked || (focused && self.input.confirm)
+    }
+
+    // 切り替えたらtrue
+    pub fn toggle(&mut self, rect: Rect, label: &str, value: &mut bool) -> bool{
+        let (index, focused, clicked) = self.focusable(&rect);
+        let input = self.input;
+        let changed = clicked || (focused && (input.confirm || input.decrease || input.increase));
+        if changed {
+            *value = !*value;
+        }
+        self.widgets.push((Some(index), Widget::Toggle{rect, label: label.to_string(), on: *value}));
+        changed
+    }
+
+    // 0.0から1.0のスライダー。左右キーで10%ずつ、クリックした位置の値にする。変わったらtrue
+    pub fn slider(&mut self, rect: Rect, label: &str, value: &mut f32) -> bool{
+        let (index, focused, clicked) = self.focusable(&rect);
+        let previous = *value;
+        let track = slider_track(&rect);
+        match self.input.click {
+            Some(point) if clicked && point.x >= track.x() => {
+                *value = (point.x - track.x()) as f32 / track.width as f32;
+            }
+            _ if focused && self.input.decrease => *value -= GUI_SLIDER_STEP,
+            _ if focused && self.input.increase => *value += GUI_SLIDER_STEP,
+            _ => {}
+        }
+        *value = ((*value / GUI_SLIDER_STEP).round() * GUI_SLIDER_STEP).clamp(0.0, 1.0);
+        self.widgets.push((Some(index), Widget::Slider{rect, label: label.to_string(), value: *value}));
+        *value != previous
+    }
+
+    pub fn text(&mut self, position: Point, text: &str){
+        self.widgets.push((None, Widget::Text{position, text: text.to_string()}));
     }
+
+    // マウスが乗るかクリックされたらフォーカスを移す
+    fn focusable(&mut self, rect: &Rect) -> (usize, bool, bool){
+        let index = self.count;
+        self.count += 1;
+        if self.input.pointer.map_or(false, |point| rect.contains(&point)) {
+            self.focus = index;
+        }
+        let clicked = self.input.click.map_or(false, |point| rect.contains(&point));
+        if clicked {
+            self.focus = index;
+        }
+        (index, self.focus == index, clicked)
+    }
+
+    pub fn draw(&self, renderer: &Renderer, font: &BitmapFont){
+        for (index, widget) in self.widgets.iter() {
+            let style = if *index == Some(self.focus) { &GUI_FOCUSED_STYLE } else { &GUI_STYLE };
+            match widget {
+                Widget::Button{rect, label} => {
+                    renderer.draw_shape(&Shape::RoundedRect(rect, GUI_PADDING), style);
+                    let (width, height) = font.measure(label);
+                    font.draw_text(renderer, label, &Point{
+                        x: rect.x() + (rect.width - width) / 2,
+                        y: rect.y() + (rect.height - height) / 2,
+                    });
+                }
+                Widget::Toggle{rect, label, on} => {
+                    renderer.draw_shape(&Shape::RoundedRect(rect, GUI_PADDING), style);
+                    draw_widget_label(renderer, font, rect, label);
+                    let size = rect.height - GUI_PADDING * 2;
+                    let check = Rect::new_from_x_y(rect.right() - GUI_PADDING - size, rect.y() + GUI_PADDING, size, size);
+                    let check_style = if *on { &GUI_FILL_STYLE } else { &GUI_TRACK_STYLE };
+                    renderer.draw_shape(&Shape::Rect(&check), check_style);
+                }
+                Widget::Slider{rect, label, value} => {
+                    renderer.draw_shape(&Shape::RoundedRect(rect, GUI_PADDING), style);
+                    draw_widget_label(renderer, font, rect, label);
+                    let track = slider_track(rect);
+                    let filled = Rect::new(track.position, (track.width as f32 * value) as i16, track.height);
+                    renderer.draw_shape(&Shape::Rect(&track), &GUI_TRACK_STYLE);
+                    renderer.draw_shape(&Shape::Rect(&filled), &GUI_FILL_STYLE);
+                }
+                Widget::Text{position, text} => font.draw_text(renderer, text, position),
+            }
+        }
+    }
+}
+
+fn draw_widget_label(renderer: &Renderer, font: &BitmapFont, rect: &Rect, label: &str){
+    let (_, height) = font.measure(label);
+    font.draw_text(renderer, label, &Point{x: rect.x() + GUI_PADDING, y: rect.y() + (rect.height - height) / 2});
+}
+
+// スライダーは右半分を値の帯にする
+fn slider_track(rect: &Rect) -> Rect{
+    Rect::new_from_x_y(
+        rect.x() + rect.width / 2,
+        rect.y() + (rect.height - GUI_TRACK_HEIGHT) / 2,
+        rect.width / 2 - GUI_PADDING,
+        GUI_TRACK_HEIGHT,
+    )
+}
+
+// canvas上のマウスの位置とクリック
+#[derive(Clone, Copy, Debug)]
+pub enum PointerEvent {
+    Move(Point),
+    Click(Point),
 }
 
-// UI
-pub fn add_click_handler(elem: HtmlElement) -> UnboundedReceiver<()> {
-    let (mut click_sender, click_receiver) = unbounded();
-    let on_click = browser::closure_wrap(Box::new(move || {
-        click_sender.start_send(());
-    }) as Box<dyn FnMut()>); 
-    elem.set_onclick(Some(on_click.as_ref().unchecked_ref()));
-    on_click.forget();
-    click_receiver
+pub fn add_pointer_handler(
+    canvas: &HtmlCanvasElement,
+    listeners: &mut Listeners,
+) -> Result<UnboundedReceiver<PointerEvent>> {
+    let (move_sender, pointer_receiver) = unbounded();
+    let click_sender = move_sender.clone();
+    let position = |event: &MouseEvent| Point {
+        x: event.offset_x() as i16,
+        y: event.offset_y() as i16,
+    };
+
+    listeners.add(canvas, "mousemove", move |event: MouseEvent| {
+        let _ = move_sender.unbounded_send(PointerEvent::Move(position(&event)));
+    })?;
+    listeners.add(canvas, "click", move |event: MouseEvent| {
+        let _ = click_sender.unbounded_send(PointerEvent::Click(position(&event)));
+    })?;
+    Ok(pointer_receiver)
+}
+
+// targetからフォーカスが外れたら通知する。ウィンドウが裏に回ったときも届く
+pub fn add_blur_handler(target: &HtmlElement, listeners: &mut Listeners) -> Result<UnboundedReceiver<()>> {
+    let (blur_sender, blur_receiver) = unbounded();
+    listeners.add(target, "blur", move |_: web_sys::Event| {
+        let _ = blur_sender.unbounded_send(());
+    })?;
+    Ok(blur_receiver)
 }
 
 //test
@@ -465,6 +1813,288 @@ mod tests {
             width: 100,
         };
 
-        assert_eq!(rect2.intersects(&rect1), true);
+        assert!(rect2.intersects(&rect1));
+    }
+
+    #[test]
+    fn glyph_layout_advances_and_wraps_lines() {
+        let layout = GlyphLayout {
+            advances: HashMap::from([('A', 12), ('B', 10), (' ', 8)]),
+            line_height: 18,
+        };
+
+        let positions = layout.positions("ab?\nB", &Point { x: 5, y: 0 });
+        let pens: Vec<(char, i16, i16)> = positions.iter().map(|(c, p)| (*c, p.x, p.y)).collect();
+
+        assert_eq!(pens, vec![('A', 5, 0), ('B', 17, 0), ('B', 5, 18)]);
+        assert_eq!(layout.measure("ab?\nB"), (30, 36));
+    }
+
+    #[test]
+    fn shape_styles_combine_fill_stroke_and_alpha() {
+        let style = ShapeStyle::fill("#000000").with_stroke("#FFFFFF", 3.0).with_alpha(0.5);
+
+        assert_eq!(style.fill.as_deref(), Some("#000000"));
+        assert_eq!(style.stroke.as_deref(), Some("#FFFFFF"));
+        assert_eq!(style.line_width, 3.0);
+        assert_eq!(style.alpha, 0.5);
+        assert_eq!(ShapeStyle::stroke("#FF0000", 2.0).fill, None);
+    }
+
+    #[test]
+    fn shape_colours_can_be_built_at_runtime() {
+        let level = 200;
+        let style = ShapeStyle::stroke("#FF0000", 1.0).with_stroke(format!("rgb({}, 64, 0)", level), 2.0);
+
+        assert_eq!(style.stroke.as_deref(), Some("rgb(200, 64, 0)"));
+    }
+
+    #[test]
+    fn rounded_corners_fit_inside_the_rect() {
+        let rect = Rect::new_from_x_y(0, 0, 200, 30);
+
+        assert_eq!(corner_radius(&rect, 10), 10);
+        assert_eq!(corner_radius(&rect, 40), 15);
+        assert_eq!(corner_radius(&rect, -5), 0);
+    }
+
+    fn layout(rects: &[Rect]) -> Vec<(i16, i16, i16, i16)> {
+        rects.iter().map(|rect| (rect.x(), rect.y(), rect.width, rect.height)).collect()
+    }
+
+    #[test]
+    fn default_draw_options_pivot_on_the_centre_without_scaling() {
+        let transform = DrawOptions::default().transform(&Rect::new_from_x_y(100, 200, 60, 40));
+
+        assert_eq!((transform.origin.x, transform.origin.y), (130, 220));
+        assert_eq!((transform.scale_x, transform.scale_y), (1.0, 1.0));
+        assert_eq!(layout(&[transform.local_destination]), vec![(-30, -20, 60, 40)]);
+    }
+
+    #[test]
+    fn flips_become_negative_scale_around_the_given_pivot() {
+        let options = DrawOptions {
+            flip_horizontal: true,
+            pivot: Some(Point { x: 0, y: 40 }),
+            scale_y: 2.0,
+            ..DrawOptions::default()
+        };
+
+        let transform = options.transform(&Rect::new_from_x_y(100, 200, 60, 40));
+
+        assert_eq!((transform.origin.x, transform.origin.y), (100, 240));
+        assert_eq!((transform.scale_x, transform.scale_y), (-1.0, 2.0));
+        assert_eq!(layout(&[transform.local_destination]), vec![(0, -40, 60, 40)]);
+    }
+
+    #[test]
+    fn draw_queue_sorts_by_layer_then_z_keeping_submission_order() {
+        let mut queue = DrawQueue::new();
+        queue.submit(Layer::Hud, 0, |_| {});
+        queue.submit(Layer::Actors, 5, |_| {});
+        queue.submit(Layer::Background, 1, |_| {});
+        queue.submit(Layer::Actors, -1, |_| {});
+        queue.submit(Layer::Background, 1, |_| {});
+
+        queue.sort();
+        let keys: Vec<(Layer, i16)> = queue.items.iter().map(|item| (item.layer, item.z)).collect();
+
+        assert_eq!(
+            keys,
+            vec![
+                (Layer::Background, 1),
+                (Layer::Background, 1),
+                (Layer::Actors, -1),
+                (Layer::Actors, 5),
+                (Layer::Hud, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn parallax_offset_wraps_within_one_image_width() {
+        assert_eq!(wrap_offset(-10.0, 100.0), -10.0);
+        assert_eq!(wrap_offset(-100.0, 100.0), 0.0);
+        assert_eq!(wrap_offset(-130.0, 100.0), -30.0);
+        assert_eq!(wrap_offset(20.0, 100.0), -80.0);
+        assert_eq!(wrap_offset(-5.0, 0.0), 0.0);
+    }
+
+    fn tileset_config() -> TilesetConfig {
+        let tile = |sprite: &str, solid: bool| TileDefinition {
+            sprite: sprite.to_string(),
+            solid,
+        };
+        TilesetConfig {
+            tile_width: 128,
+            tile_height: 93,
+            tiles: HashMap::from([(1, tile("1.png", true)), (2, tile("2.png", false)), (7, tile("missing.png", true))]),
+        }
+    }
+
+    fn cell(x: i16, y: i16, w: i16, h: i16) -> Cell {
+        Cell {
+            frame: SheetRect { x, y, w, h },
+            sprite_source_size: SheetRect { x: 0, y: 0, w, h },
+        }
+    }
+
+    #[test]
+    fn tile_ids_map_to_their_sheet_frames() {
+        let config = tileset_config();
+        let sheet = Sheet {
+            frames: HashMap::from([
+                ("1.png".to_string(), cell(0, 0, 128, 93)),
+                ("2.png".to_string(), cell(130, 0, 128, 80)),
+            ]),
+        };
+
+        assert_eq!(config.frame(&sheet, 2).map(|frame| layout(&[frame])), Some(vec![(130, 0, 128, 80)]));
+        assert!(config.frame(&sheet, 7).is_none());
+        assert!(config.frame(&sheet, 3).is_none());
+    }
+
+    #[test]
+    fn tile_grid_places_tiles_row_by_row_and_skips_blanks() {
+        let config = tileset_config();
+        let grid = TileGrid::new(3, vec![0, 2, 0, 1, 3, 7, 1], Point { x: 100, y: 50 });
+
+        let cells: Vec<(usize, usize, u16)> = grid.cells(&config).collect();
+
+        assert_eq!(grid.rows(), 3);
+        assert_eq!(cells, vec![(1, 0, 2), (0, 1, 1), (2, 1, 7), (0, 2, 1)]);
+        assert_eq!(layout(&[grid.tile_box(&config, 2, 1)]), vec![(356, 143, 128, 93)]);
+        assert_eq!(grid.right(&config), 484);
+    }
+
+    #[test]
+    fn tile_grid_reports_boxes_only_for_solid_tiles() {
+        let config = tileset_config();
+        let grid = TileGrid::new(2, vec![2, 1, 7, 0], Point { x: 0, y: 10 });
+
+        assert_eq!(layout(&grid.solid_boxes(&config)), vec![(128, 10, 128, 93), (0, 103, 128, 93)]);
+    }
+
+    #[test]
+    fn oscillating_motion_returns_to_its_origin_each_period() {
+        let mut motion = Motion::new(MotionPath::Oscillate {
+            amplitude: Point { x: 0, y: 40 },
+            period: 60,
+        });
+
+        let deltas: Vec<i16> = (0..60).map(|_| motion.advance().y).collect();
+
+        assert_eq!(deltas[..15].iter().sum::<i16>(), 40);
+        assert_eq!(deltas.iter().sum::<i16>(), 0);
+    }
+
+    #[test]
+    fn waypoint_motion_reaches_each_point_at_leg_boundaries() {
+        let mut motion = Motion::new(MotionPath::Waypoints {
+            points: vec![Point { x: 0, y: 0 }, Point { x: 100, y: -50 }],
+            frames_per_leg: 10,
+            easing: Easing::EaseInOut,
+        });
+        let mut travel = |frames: usize| {
+            (0..frames).map(|_| motion.advance()).fold((0, 0), |(x, y), delta| (x + delta.x, y + delta.y))
+        };
+
+        assert_eq!(travel(10), (100, -50));
+        assert_eq!(travel(10), (-100, 50));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn motion_trails_span_the_whole_path() {
+        let oscillate = Motion::new(MotionPath::Oscillate {
+            amplitude: Point { x: 30, y: 0 },
+            period: 60,
+        });
+        let (points, closed) = oscillate.trail();
+        assert_eq!(points.iter().map(|point| point.x).collect::<Vec<_>>(), vec![-30, 30]);
+        assert!(!closed);
+
+        let mut waypoints = Motion::new(MotionPath::Waypoints {
+            points: vec![Point { x: 0, y: 0 }, Point { x: 100, y: -50 }, Point { x: 0, y: -50 }],
+            frames_per_leg: 10,
+            easing: Easing::Linear,
+        });
+        (0..10).for_each(|_| {
+            waypoints.advance();
+        });
+        let (points, closed) = waypoints.trail();
+        assert_eq!(points.iter().map(|point| (point.x, point.y)).collect::<Vec<_>>(), vec![(0, 0), (100, -50), (0, -50)]);
+        assert!(closed);
+        assert_eq!((waypoints.offset().x, waypoints.offset().y), (100, -50));
+    }
+
+    #[test]
+    fn hitbox_for_frame_overrides_animation() {
+        let hitboxes: Hitboxes = serde_json::from_str(
+            r#"{
+                "animations": {"Run": {"x": 76, "y": 22, "w": 43, "h": 101}},
+                "frames": {"Run (2).png": {"x": 70, "y": 30, "w": 50, "h": 90}}
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(hitboxes.get("Run", "Run (1).png").map(|hitbox| hitbox.y()), Some(22));
+        assert_eq!(hitboxes.get("Run", "Run (2).png").map(|hitbox| hitbox.y()), Some(30));
+        assert!(hitboxes.get("Slide", "Slide (1).png").is_none());
+    }
+
+    #[test]
+    fn gui_focus_wraps_and_confirm_activates_the_focused_button() {
+        let mut gui = Gui::default();
+        let rect = |y| Rect::new_from_x_y(0, y, 100, 20);
+        let frame = |gui: &mut Gui, input: GuiInput| {
+            gui.begin(input);
+            (gui.button(rect(0), "A"), gui.button(rect(30), "B"))
+        };
+        frame(&mut gui, GuiInput::default());
+
+        frame(&mut gui, GuiInput { previous: true, ..GuiInput::default() });
+        let pressed = frame(&mut gui, GuiInput { confirm: true, ..GuiInput::default() });
+
+        assert_eq!(pressed, (false, true));
+    }
+
+    #[test]
+    fn gui_slider_steps_with_keys_and_follows_clicks() {
+        let mut gui = Gui::default();
+        let rect = Rect::new_from_x_y(0, 0, 200, 20);
+        let mut value = 1.0;
+
+        gui.begin(GuiInput { increase: true, ..GuiInput::default() });
+        assert!(!gui.slider(rect, "VOLUME", &mut value));
+
+        let track = slider_track(&rect);
+        let click = Point { x: track.x() + track.width / 2, y: 10 };
+        gui.begin(GuiInput { click: Some(click), ..GuiInput::default() });
+        assert!(gui.slider(rect, "VOLUME", &mut value));
+        assert!((value - 0.5).abs() < f32::EPSILON);
+    }
+
+    #[test]
+    fn releasing_all_keys_keeps_nothing_held() {
+        let mut keystate = KeyState::new();
+        keystate.set_pressed("ArrowRight");
+        keystate.set_pressed("Space");
+
+        keystate.release_all();
+
+        assert!(!keystate.is_pressed("ArrowRight"));
+        assert!(!keystate.is_pressed("Space"));
+    }
+
+    #[test]
+    fn the_first_key_pressed_in_an_update_is_reported() {
+        let mut keystate = KeyState::new();
+        ["KeyW", "KeyA", "KeyS", "KeyD"].iter().for_each(|key| keystate.set_pressed(key));
+
+        assert_eq!(keystate.first_pressed(), Some("KeyW"));
+        assert!(keystate.was_pressed("KeyD"));
+
+        keystate.end_update();
+        assert_eq!(keystate.first_pressed(), None);
+    }
+}
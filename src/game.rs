@@ -1,26 +1,80 @@
 use anyhow::{anyhow, Result};
 use async_trait::async_trait;
-use serde::Deserialize;
-use std::collections::HashMap;
-use rand::prelude::*;
+use rand::{prelude::*, rngs::StdRng};
 use gloo_utils::format::JsValueSerdeExt;
 use std::rc::Rc;
 use futures::channel::mpsc::UnboundedReceiver;
 
-use web_sys::HtmlImageElement;
+use web_sys::{Element, HtmlImageElement};
 use self::red_hat_boy_states::*;
+pub use self::red_hat_boy_states::jump_offset;
 
 use crate::{
-    browser,
+    browser::{self, Listeners},
+    control::{Command, Control, GameConfig, GameEvent},
     engine::{
-        self, Cell, Game, Image, KeyState, Point, Rect, Renderer, Sheet, SpriteSheet, Audio, Sound,
+        self, BitmapFont, GameContext, RenderSettings, Cell, GAMEPAD_B, Gui, GuiInput, PointerEvent, DrawOptions, DrawQueue, FontDescriptor, Game, Hitboxes, Image, KeyState, Layer,
+        Motion, Parallax, ParallaxConfig, Point, Rect, Renderer, Shape, ShapeStyle, Sheet, SpriteSheet, Tilemap,
+        Tileset, TilesetConfig, Tint, Audio, Sound,
     }, 
-    segments::{stone_and_platform, platform_and_stone},
+    segments::{
+        coins_over_stone, ground_block, hanging_stones, long_platform_over_stones, moving_platforms, pits,
+        platform_and_stone, stone_and_platform, TiledSegment,
+    },
+    high_scores::{HighScore, HighScores},
+    leaderboard::{HttpLeaderboard, Leaderboard, LeaderboardEntry, Replay, Run},
+    score::Score,
+    settings::{key_label, Action, Settings, ACTIONS},
+    tiled::TiledMap,
+    ui::{self, Panel},
 };
 
 const HEIGHT: i16 = 600;
+const WIDTH: i16 = 600;
+const READY_PROMPT_Y: i16 = 200;
+const PROMPT_PADDING: i16 = 10;
+const HIT_FLASH_FRAMES: u8 = 4;
+const HIT_FLASH_TINT: Tint = Tint { color: "#FF0000", strength: 0.6 };
+const GROUND_SURFACE_HEIGHT: i16 = 20;
+const GROUND_STYLE: ShapeStyle = ShapeStyle::fill("#8C5A2B");
+const HUD_POSITION: Point = Point { x: 10, y: 10 };
+const COIN_SIZE: i16 = 32;
+const COIN_FRAMES: u8 = 8;
+const COIN_FRAME_TICKS: u8 = 4;
+const COIN_POINTS: u32 = 10;
+const JUMP_BONUS: u32 = 25;
+const SLIDE_BONUS: u32 = 25;
+const GAME_OVER_SCORE_Y: i16 = 110;
+const HIGH_SCORES_Y: i16 = 260;
+const GAME_TITLE: &str = "WALK THE DOG";
+const GAME_TITLE_Y: i16 = 110;
+const MENU_PAGE_Y: i16 = 100;
+const MENU_BACK_KEYS: [&str; 4] = ["Escape", "Backspace", "Enter", GAMEPAD_B];
+const SETTINGS_BACK_KEYS: [&str; 3] = ["Escape", "Backspace", GAMEPAD_B];
+const MENU_KEYS: [&str; 8] = [
+    "ArrowUp", "ArrowDown", "ArrowLeft", "ArrowRight", "Enter", "Space", "Escape", "Backspace",
+];
+const SETTINGS_TITLE: &str = "SETTINGS";
+const SETTINGS_TITLE_Y: i16 = 30;
+const BACK_PROMPT: &str = "PRESS ESC TO GO BACK";
+const CREDITS: &str = "CREDITS\n\nBASED ON GAME DEVELOPMENT\nWITH RUST AND WEBASSEMBLY\n\nRED HAT BOY AND TILES\nGAMEART2D.COM\n\nUI BUTTONS AND FONT\nKENNEY.NL";
+const PAUSED_PROMPT: &str = "PAUSED";
+const PAUSED_PROMPT_Y: i16 = 120;
+// ゲームオーバー画面から遊び直せるハイスコアのシードの数
+const RETRY_SEEDS: usize = 3;
+// ビルド時に決めるランキングサーバーのURL。startのleaderboardUrlが優先で、どちらも無ければ送らない
+const LEADERBOARD_URL: Option<&str> = option_env!("WALK_THE_DOG_LEADERBOARD_URL");
+const ONLINE_TOP_COUNT: usize = 3;
+const REPLAY_ACTIONS: [(Action, &str); 2] = [(Action::Slide, "slide"), (Action::Jump, "jump")];
+const ROPE_WIDTH: i16 = 4;
+const ROPE_STYLE: ShapeStyle = ShapeStyle::fill("#6B4A2B");
+// デバッグ表示で動く足場の今の位置に付ける印
+const MOTION_MARKER_RADIUS: i16 = 4;
+const PROMPT_PANEL_STYLE: ShapeStyle = ShapeStyle::fill("#000000").with_alpha(0.5);
 const TIMELINE_MINIMUM: i16 = 1000;
 const OBSTACLE_BUFFER:i16 = 20;
+const TILED_SEGMENTS: [&str; 1] = ["tiled/ledge.json"];
+const BUILT_IN_SEGMENTS: usize = 8;
 
 
 // pub enum WalkTheDog{
@@ -30,33 +84,93 @@ const OBSTACLE_BUFFER:i16 = 20;
 
 pub struct WalkTheDog{
     machine: Option<WalkTheDogStateMachine>,
+    config: GameConfig,
+    control: Control,
 }
 
 enum WalkTheDogStateMachine{
+    Title(WalkTheDogState<Title>),
     Ready(WalkTheDogState<Ready>),
     Walking(WalkTheDogState<Walking>),
+    Paused(WalkTheDogState<Paused>),
     GameOver(WalkTheDogState<GameOver>),
 }
 
 impl WalkTheDogStateMachine {
     fn new(walk: Walk) -> Self {
-        WalkTheDogStateMachine::Ready(WalkTheDogState::new(walk))
+        WalkTheDogStateMachine::Title(WalkTheDogState::<Title>::new(walk))
+    }
+
+    // ページ側からの命令。今の状態で意味の無い命令は無視する
+    fn command(self, command: Command) -> Self {
+        match (self, command) {
+            (WalkTheDogStateMachine::Walking(state), Command::Pause) => state.pause().into(),
+            (WalkTheDogStateMachine::Paused(state), Command::Resume) => state.resume().into(),
+            (machine, Command::Restart(seed)) => machine.restart(seed.unwrap_or_else(new_seed)),
+            (machine, _) => machine,
+        }
+    }
+
+    fn restart(self, seed: u32) -> Self {
+        let walk = match self {
+            WalkTheDogStateMachine::Title(state) => state.walk,
+            WalkTheDogStateMachine::Ready(state) => state.walk,
+            WalkTheDogStateMachine::Walking(state) => state.walk,
+            WalkTheDogStateMachine::Paused(state) => {
+                state.close_menu();
+                state.walk
+            }
+            WalkTheDogStateMachine::GameOver(state) => {
+                state.walk.focus();
+                state.walk
+            }
+        };
+        WalkTheDogState::<Ready>::new(Walk::reset_with_seed(walk, seed)).into()
     }
 
     fn update(self, keystate: &KeyState) -> Self {
         match self {
+            WalkTheDogStateMachine::Title(state) => state.update(keystate).into(),
             WalkTheDogStateMachine::Ready(state) => state.update(keystate).into(),
             WalkTheDogStateMachine::Walking(state) => state.update(keystate).into(),
-            WalkTheDogStateMachine::GameOver(state) => state.update().into(),
+            WalkTheDogStateMachine::Paused(state) => state.update(keystate).into(),
+            WalkTheDogStateMachine::GameOver(state) => state.update(keystate).into(),
+        }
+    }
+
+    fn walk(&self) -> &Walk {
+        match self {
+            WalkTheDogStateMachine::Title(state) => &state.walk,
+            WalkTheDogStateMachine::Ready(state) => &state.walk,
+            WalkTheDogStateMachine::Walking(state) => &state.walk,
+            WalkTheDogStateMachine::Paused(state) => &state.walk,
+            WalkTheDogStateMachine::GameOver(state) => &state.walk,
         }
     }
 
     fn draw(&self, renderer: &Renderer) {
+        renderer.apply_settings(self.walk().render_settings());
+        let mut queue = DrawQueue::new();
         match self {
-            WalkTheDogStateMachine::Ready(state) => state.draw(renderer),
-            WalkTheDogStateMachine::Walking(state) => state.draw(renderer),
-            WalkTheDogStateMachine::GameOver(state) => state.draw(renderer),
+            WalkTheDogStateMachine::Title(state) => {
+                state.draw(&mut queue);
+                state.draw_menu(&mut queue);
+            }
+            WalkTheDogStateMachine::Ready(state) => {
+                state.draw(&mut queue);
+                state.draw_prompt(&mut queue);
+            }
+            WalkTheDogStateMachine::Walking(state) => state.draw(&mut queue),
+            WalkTheDogStateMachine::Paused(state) => {
+                state.draw(&mut queue);
+                state.draw_overlay(&mut queue);
+            }
+            WalkTheDogStateMachine::GameOver(state) => {
+                state.draw(&mut queue);
+                state.draw_breakdown(&mut queue);
+            }
         }
+        queue.draw(renderer);
     }
 }
 
@@ -66,8 +180,145 @@ struct WalkTheDogState<T> {
 }
 
 impl<T> WalkTheDogState<T> {
-    fn draw(&self, renderer: &Renderer) {
-        self.walk.draw(renderer);
+    fn draw<'a>(&'a self, queue: &mut DrawQueue<'a>) {
+        self.walk.draw(queue);
+    }
+}
+
+impl WalkTheDogState<Title> {
+    fn new(mut walk: Walk) -> WalkTheDogState<Title> {
+        // メニューの外で溜まったマウス操作は捨てる
+        walk.pointer_events();
+        WalkTheDogState {
+            _state: Title {
+                gui: Gui::default(),
+                page: Page::Menu,
+            },
+            walk,
+        }
+    }
+
+    fn update(mut self, keystate: &KeyState) -> TitleEndState {
+        self.walk.boy.update();
+        let pointer_events = self.walk.pointer_events();
+
+        let page = match self._state.page {
+            Page::Menu => {
+                let gui = &mut self._state.gui;
+                gui.begin(GuiInput::new(keystate, &pointer_events));
+                let mut chosen = None;
+                MENU_ITEMS.iter().enumerate().for_each(|(index, item)| {
+                    if gui.button(MAIN_MENU.rect(index), item.label()) {
+                        chosen = Some(*item);
+                    }
+                });
+                match chosen {
+                    Some(MenuItem::Play) => {
+                        return TitleEndState::Complete(WalkTheDogState::<Ready>::new(self.walk));
+                    }
+                    Some(MenuItem::Settings) => Page::Settings { rebinding: None },
+                    Some(MenuItem::HighScores) => Page::HighScores,
+                    Some(MenuItem::Credits) => Page::Credits,
+                    None => Page::Menu,
+                }
+            }
+            Page::Settings { rebinding } => self.update_settings(rebinding, keystate, &pointer_events),
+            Page::HighScores | Page::Credits => {
+                let clicked = pointer_events
+                    .iter()
+                    .any(|event| matches!(event, PointerEvent::Click(_)));
+                if clicked || MENU_BACK_KEYS.iter().any(|key| keystate.was_pressed(key)) {
+                    Page::Menu
+                } else {
+                    self._state.page
+                }
+            }
+        };
+
+        // 画面が変わったらフォーカスは先頭から
+        if std::mem::discriminant(&page) != std::mem::discriminant(&self._state.page) {
+            self._state.gui = Gui::default();
+        }
+        self._state.page = page;
+        TitleEndState::Continue(self)
+    }
+
+    fn update_settings(
+        &mut self,
+        rebinding: Option<Action>,
+        keystate: &KeyState,
+        pointer_events: &[PointerEvent],
+    ) -> Page {
+        // 割り当て中は次に押されたキーをその操作に割り当て、メニューは操作させない
+        if let Some(action) = rebinding {
+            if keystate.was_pressed("Escape") {
+                return self.settings_gui(GuiInput::default(), None);
+            }
+            return match keystate.first_pressed() {
+                Some(code) => {
+                    self.walk.settings.bindings.bind(action, code);
+                    self.walk.settings_changed();
+                    self.settings_gui(GuiInput::default(), None)
+                }
+                None => self.settings_gui(GuiInput::default(), rebinding),
+            };
+        }
+        if SETTINGS_BACK_KEYS.iter().any(|key| keystate.was_pressed(key)) {
+            return Page::Menu;
+        }
+        self.settings_gui(GuiInput::new(keystate, pointer_events), None)
+    }
+
+    // 設定画面のウィジェットを並べ、操作された結果の画面を返す
+    fn settings_gui(&mut self, input: GuiInput, rebinding: Option<Action>) -> Page {
+        let gui = &mut self._state.gui;
+        let settings = &mut self.walk.settings;
+        let row = |index: usize| SETTINGS_MENU.rect(index);
+        gui.begin(input);
+
+        let mut changed = gui.slider(row(0), "MUSIC", &mut settings.music_volume);
+        changed |= gui.slider(row(1), "SOUND", &mut settings.sfx_volume);
+        let mut next = rebinding;
+        ACTIONS.iter().enumerate().for_each(|(index, action)| {
+            let label = match rebinding {
+                Some(rebinding) if rebinding == *action => format!("{}  PRESS A KEY", action.label()),
+                _ => format!("{}  {}", action.label(), key_label(settings.bindings.key(*action))),
+            };
+            if gui.button(row(2 + index), &label) {
+                next = Some(*action);
+            }
+        });
+        changed |= gui.toggle(row(6), "SHOW FPS", &mut settings.show_fps);
+        changed |= gui.toggle(row(7), "REDUCED MOTION", &mut settings.reduced_motion);
+        let back = gui.button(row(8), "BACK");
+        gui.text(Point { x: row(9).x(), y: row(9).y() }, BACK_PROMPT);
+
+        if changed {
+            self.walk.settings_changed();
+        }
+        if back {
+            Page::Menu
+        } else {
+            Page::Settings { rebinding: next }
+        }
+    }
+
+    fn draw_menu<'a>(&'a self, queue: &mut DrawQueue<'a>) {
+        let page = match self._state.page {
+            Page::Menu => {
+                self.walk.draw_panel(queue, GAME_TITLE.to_string(), GAME_TITLE_Y);
+                self.walk.draw_gui(queue, &self._state.gui);
+                return;
+            }
+            Page::Settings { .. } => {
+                self.walk.draw_panel(queue, SETTINGS_TITLE.to_string(), SETTINGS_TITLE_Y);
+                self.walk.draw_gui(queue, &self._state.gui);
+                return;
+            }
+            Page::HighScores => self.walk.high_scores.table(),
+            Page::Credits => CREDITS.to_string(),
+        };
+        self.walk.draw_panel(queue, format!("{}\n\n{}", page, BACK_PROMPT), MENU_PAGE_Y);
     }
 }
 
@@ -81,7 +332,7 @@ impl WalkTheDogState<Ready> {
 
     fn update(mut self, keystate: &KeyState) -> ReadyEndState {
         self.walk.boy.update();
-        if keystate.is_pressed("ArrowRight") {
+        if keystate.is_pressed(self.walk.settings.bindings.key(Action::Run)) {
             ReadyEndState::Complete(self.start_running())
         } else {
             ReadyEndState::Continue(self)
@@ -91,6 +342,9 @@ impl WalkTheDogState<Ready> {
 
 impl WalkTheDogState<Ready> {
     fn start_running(mut self) -> WalkTheDogState<Walking> {
+        self.walk.control.emit(GameEvent::Started { seed: self.walk.seed });
+        // 走り出す前に外れたフォーカスでは一時停止しない
+        self.walk.focus_lost();
         self.run_right();
         WalkTheDogState {
             _state: Walking,
@@ -101,33 +355,61 @@ impl WalkTheDogState<Ready> {
     fn run_right(&mut self) {
         self.walk.boy.run_right();
     }
+
+    fn draw_prompt<'a>(&'a self, queue: &mut DrawQueue<'a>) {
+        let key = key_label(self.walk.settings.bindings.key(Action::Run));
+        self.walk.draw_panel(queue, format!("PRESS {} TO RUN", key), READY_PROMPT_Y);
+    }
 }
 
 impl WalkTheDogState<Walking> {
     fn update(mut self, keystate: &KeyState) -> WalkingEndState {
-        if keystate.is_pressed("ArrowDown"){
+        if self.walk.pause_pressed(keystate) || self.walk.focus_lost() {
+            return WalkingEndState::Paused(self.pause());
+        }
+
+        let bindings = &self.walk.settings.bindings;
+        if keystate.is_pressed(bindings.key(Action::Slide)){
             self.walk.boy.slide();
         }
-        if keystate.is_pressed("Space"){
+        if keystate.is_pressed(bindings.key(Action::Jump)){
             self.walk.boy.jump();
         }
         self.walk.boy.update();
-
-        let walk_speed = self.walk.velocity();
-        let [first_background, second_background] = &mut self.walk.backgrounds;
-        first_background.move_horizontally(walk_speed);
-        second_background.move_horizontally(walk_speed);
-        if first_background.right() < 0 {
-            first_background.set_x(second_background.right());
-        }
-        if second_background.right() < 0 {
-            second_background.set_x(first_background.right());
+        // 穴に落ちて画面の下まで行ったら倒れる
+        if self.walk.boy.fell_off_screen() {
+            self.walk.boy.knock_out();
         }
 
-        self.walk.obstacles.retain(|obstacle| obstacle.right() > 0);
+        // キーの割り当てに関係なく再生できるように操作名で記録する
+        self.walk.frame += 1;
+        REPLAY_ACTIONS.iter().for_each(|(action, name)| {
+            let pressed = keystate.is_pressed(self.walk.settings.bindings.key(*action));
+            self.walk.replay.record(self.walk.frame, name, pressed);
+        });
+
+        let walk_speed = self.walk.velocity();
+        self.walk.background.scroll(walk_speed);
+        self.walk.score.run(-walk_speed);
+
+        self.walk
+            .obstacles
+            .retain(|obstacle| obstacle.right() > 0 && !obstacle.collected());
+        // 地面の上にいるかどうかは毎フレーム地面から判定し直す
+        self.walk.boy.set_on_ground(false);
         self.walk.obstacles.iter_mut().for_each(|obstacle| {
+            obstacle.update();
             obstacle.move_horizontally(walk_speed);
             obstacle.check_intersection(&mut self.walk.boy);
+            if let Some(points) = obstacle.clear(&self.walk.boy) {
+                self.walk.score.bonus(points);
+            }
+            if let Some(points) = obstacle.collect(&self.walk.boy) {
+                self.walk.score.collect(points);
+                if let Err(err) = self.walk.audio.play_sound(&self.walk.pickup_sound) {
+                    log!("Error playing pickup sound {:#?}", err);
+                }
+            }
         });
 
         if self.walk.timeline < TIMELINE_MINIMUM {
@@ -143,35 +425,253 @@ impl WalkTheDogState<Walking> {
         }
     }
 
-    fn end_game(self) -> WalkTheDogState<GameOver> {
-        let receiver = browser::draw_ui("<button id='new_game'>New Game</button>")
-            .and_then(|_unit| browser::find_html_element_by_id("new_game"))
-            .map(|element| engine::add_click_handler(element))
-            .unwrap();
+    fn pause(mut self) -> WalkTheDogState<Paused> {
+        self.walk.control.emit(GameEvent::Paused);
+        if let Err(err) = self.walk.audio.suspend() {
+            log!("Error suspending audio {:#?}", err);
+        }
+        let settings = &self.walk.settings;
+        let menu = self.walk.context.ui_root.as_ref().and_then(|ui| match PauseMenu::new(ui, settings) {
+            Ok(menu) => Some(menu),
+            Err(err) => {
+                log!("Could not show the pause menu {:#?}", err);
+                None
+            }
+        });
+        // 走っている間に溜まったマウス操作は捨てる
+        self.walk.pointer_events();
+
+        WalkTheDogState {
+            _state: Paused {
+                menu,
+                gui: Gui::default(),
+            },
+            walk: self.walk,
+        }
+    }
+
+    fn end_game(mut self) -> WalkTheDogState<GameOver> {
+        self.walk.control.emit(GameEvent::GameOver {
+            score: self.walk.score.total(),
+            seed: self.walk.seed,
+        });
+        let date = browser::today();
+        let rank = self.walk.high_scores.record(HighScore {
+            score: self.walk.score.total(),
+            date: date.clone(),
+            seed: self.walk.seed,
+        });
+        let online_top_event = self.walk.submit_run(Run {
+            score: self.walk.score.total(),
+            seed: self.walk.seed,
+            date,
+            replay: self.walk.replay.clone(),
+        });
+        let retry_entries: Vec<HighScore> = self
+            .walk
+            .high_scores
+            .entries()
+            .iter()
+            .take(RETRY_SEEDS)
+            .cloned()
+            .collect();
+        let menu = self.walk.context.ui_root.as_ref().and_then(|ui| match GameOverMenu::new(ui, &retry_entries) {
+            Ok(menu) => Some(menu),
+            Err(err) => {
+                log!("Could not show the game over menu {:#?}", err);
+                None
+            }
+        });
+        self.walk.pointer_events();
 
         WalkTheDogState {
             _state: GameOver{
-                new_game_event: receiver,
+                menu,
+                gui: Gui::default(),
+                retry_seeds: retry_entries.iter().map(|entry| entry.seed).collect(),
+                rank,
+                online_top_event,
+                online_top: Vec::new(),
             },
             walk: self.walk,
         }
     }
 }
 
+impl WalkTheDogState<Paused> {
+    fn draw_overlay<'a>(&'a self, queue: &mut DrawQueue<'a>) {
+        self.walk.draw_panel(queue, PAUSED_PROMPT.to_string(), PAUSED_PROMPT_Y);
+        if self._state.menu.is_none() {
+            self.walk.draw_gui(queue, &self._state.gui);
+        }
+    }
+
+    fn update(mut self, keystate: &KeyState) -> PausedEndState {
+        let pointer_events = self.walk.pointer_events();
+        let settings = &mut self.walk.settings;
+        let mut changed = false;
+        let (resume, restart, quit) = match self._state.menu.as_mut() {
+            Some(menu) => {
+                if let Some(volume) = ui::latest(&mut menu.music_event) {
+                    settings.music_volume = volume;
+                    changed = true;
+                }
+                if let Some(volume) = ui::latest(&mut menu.sfx_event) {
+                    settings.sfx_volume = volume;
+                    changed = true;
+                }
+                (
+                    pressed(&mut menu.resume_event),
+                    pressed(&mut menu.restart_event),
+                    pressed(&mut menu.quit_event),
+                )
+            }
+            // DOMのメニューが無いときはcanvasに描く
+            None => {
+                let gui = &mut self._state.gui;
+                gui.begin(GuiInput::new(keystate, &pointer_events));
+                let buttons = (
+                    gui.button(PAUSE_MENU.rect(0), "RESUME"),
+                    gui.button(PAUSE_MENU.rect(1), "RESTART"),
+                    gui.button(PAUSE_MENU.rect(2), "QUIT"),
+                );
+                changed |= gui.slider(PAUSE_MENU.rect(3), "MUSIC", &mut settings.music_volume);
+                changed |= gui.slider(PAUSE_MENU.rect(4), "SOUND", &mut settings.sfx_volume);
+                buttons
+            }
+        };
+        if changed {
+            self.walk.settings_changed();
+        }
+
+        if self.walk.pause_pressed(keystate) || resume {
+            PausedEndState::Walking(self.resume())
+        } else if restart {
+            PausedEndState::Walking(self.reset().start_running())
+        } else if quit {
+            PausedEndState::Quit(self.quit())
+        } else {
+            PausedEndState::Continue(self)
+        }
+    }
+
+    fn resume(mut self) -> WalkTheDogState<Walking> {
+        self.walk.control.emit(GameEvent::Resumed);
+        self.close_menu();
+        // 一時停止中に外れたフォーカスは無視する
+        self.walk.focus_lost();
+        WalkTheDogState {
+            _state: Walking,
+            walk: self.walk,
+        }
+    }
+
+    fn reset(self) -> WalkTheDogState<Ready> {
+        self.close_menu();
+        WalkTheDogState::<Ready>::new(Walk::reset(self.walk))
+    }
+
+    fn quit(self) -> WalkTheDogState<Title> {
+        self.close_menu();
+        WalkTheDogState::<Title>::new(Walk::reset(self.walk))
+    }
+
+    fn close_menu(&self) {
+        self.walk.focus();
+        if let Err(err) = self.walk.audio.resume() {
+            log!("Error resuming audio {:#?}", err);
+        }
+    }
+}
+
 impl WalkTheDogState<GameOver> {
-    fn update(mut self) -> GameOverEndState {
-        if self._state.new_game_pressed() {
+    fn draw_breakdown<'a>(&'a self, queue: &mut DrawQueue<'a>) {
+        let mut breakdown = match self._state.rank {
+            Some(rank) => format!("{}\nNEW HIGH SCORE #{}", self.walk.score().breakdown(), rank),
+            None => self.walk.score().breakdown(),
+        };
+        if !self._state.online_top.is_empty() {
+            let scores: Vec<String> = self
+                ._state
+                .online_top
+                .iter()
+                .map(|entry| entry.score.to_string())
+                .collect();
+            breakdown.push_str(&format!("\nONLINE TOP  {}", scores.join("  ")));
+        }
+        self.walk.draw_panel(queue, breakdown, GAME_OVER_SCORE_Y);
+        self.walk.draw_high_scores(queue);
+        if self._state.menu.is_none() {
+            self.walk.draw_gui(queue, &self._state.gui);
+        }
+    }
+
+    fn update(mut self, keystate: &KeyState) -> GameOverEndState {
+        self._state.receive_online_top();
+        let pointer_events = self.walk.pointer_events();
+        let state = &mut self._state;
+        let (new_game, title, retry) = match state.menu.as_mut() {
+            Some(menu) => (
+                pressed(&mut menu.new_game_event),
+                pressed(&mut menu.title_event),
+                ui::latest(&mut menu.retry_event).and_then(|index| state.retry_seeds.get(index).copied()),
+            ),
+            None => {
+                let gui = &mut state.gui;
+                gui.begin(GuiInput::new(keystate, &pointer_events));
+                let new_game = gui.button(GAME_OVER_BUTTONS.rect(0, 2), "NEW GAME");
+                let title = gui.button(GAME_OVER_BUTTONS.rect(1, 2), "MENU");
+                // ハイスコア表の上から順に、同じシードで走り直すボタン
+                let count = state.retry_seeds.len();
+                let mut retry = None;
+                state.retry_seeds.iter().enumerate().for_each(|(index, seed)| {
+                    if gui.button(RETRY_BUTTONS.rect(index, count), &format!("RETRY {}", index + 1)) {
+                        retry = Some(*seed);
+                    }
+                });
+                (new_game, title, retry)
+            }
+        };
+
+        if new_game {
             GameOverEndState::Complete(self.new_game())
+        } else if let Some(seed) = retry {
+            GameOverEndState::Complete(self.retry(seed))
+        } else if title {
+            GameOverEndState::Title(self.title())
         } else {
             GameOverEndState::Continue(self)
         }
     }
 
     fn new_game(self) -> WalkTheDogState<Ready> {
-        browser::hide_ui();
-        WalkTheDogState {
-            _state: Ready,
-            walk: Walk::reset(self.walk),
+        self.walk.focus();
+        WalkTheDogState::<Ready>::new(Walk::reset(self.walk))
+    }
+
+    // ハイスコアと同じ並びの区間でもう一度走る
+    fn retry(self, seed: u32) -> WalkTheDogState<Ready> {
+        self.walk.focus();
+        WalkTheDogState::<Ready>::new(Walk::reset_with_seed(self.walk, seed))
+    }
+
+    fn title(self) -> WalkTheDogState<Title> {
+        self.walk.focus();
+        WalkTheDogState::<Title>::new(Walk::reset(self.walk))
+    }
+}
+
+impl From<WalkTheDogState<Title>> for WalkTheDogStateMachine {
+    fn from(state: WalkTheDogState<Title>) -> Self {
+        WalkTheDogStateMachine::Title(state)
+    }
+}
+
+impl From<TitleEndState> for WalkTheDogStateMachine {
+    fn from(end_state: TitleEndState) -> Self {
+        match end_state {
+            TitleEndState::Complete(ready) => ready.into(),
+            TitleEndState::Continue(title) => title.into(),
         }
     }
 }
@@ -188,6 +688,22 @@ impl From<WalkTheDogState<Walking>> for WalkTheDogStateMachine {
     }
 }
 
+impl From<WalkTheDogState<Paused>> for WalkTheDogStateMachine {
+    fn from(state: WalkTheDogState<Paused>) -> Self {
+        WalkTheDogStateMachine::Paused(state)
+    }
+}
+
+impl From<PausedEndState> for WalkTheDogStateMachine {
+    fn from(end_state: PausedEndState) -> Self {
+        match end_state {
+            PausedEndState::Walking(walking) => walking.into(),
+            PausedEndState::Quit(title) => title.into(),
+            PausedEndState::Continue(paused) => paused.into(),
+        }
+    }
+}
+
 impl From<WalkTheDogState<GameOver>> for WalkTheDogStateMachine {
     fn from(state: WalkTheDogState<GameOver>) -> Self {
         WalkTheDogStateMachine::GameOver(state)
@@ -198,6 +714,7 @@ impl From<WalkingEndState> for WalkTheDogStateMachine {
     fn from(end_state: WalkingEndState) -> Self {
         match end_state {
             WalkingEndState::Complete(game_over) => game_over.into(),
+            WalkingEndState::Paused(paused) => paused.into(),
             WalkingEndState::Continue(walking) => walking.into(),
         }
     }
@@ -207,24 +724,209 @@ impl From<GameOverEndState> for WalkTheDogStateMachine {
     fn from(end_state: GameOverEndState) -> Self {
         match end_state {
             GameOverEndState::Complete(ready) => ready.into(),
+            GameOverEndState::Title(title) => title.into(),
             GameOverEndState::Continue(game_over) => game_over.into(),
         }
     }
 }
 
 
+struct Title {
+    gui: Gui,
+    page: Page,
+}
+
+#[derive(Clone, Copy)]
+enum Page {
+    Menu,
+    // rebindingはキーの割り当てを変えている途中の操作
+    Settings { rebinding: Option<Action> },
+    HighScores,
+    Credits,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum MenuItem {
+    Play,
+    Settings,
+    HighScores,
+    Credits,
+}
+
+impl MenuItem {
+    fn label(&self) -> &'static str {
+        match self {
+            MenuItem::Play => "PLAY",
+            MenuItem::Settings => "SETTINGS",
+            MenuItem::HighScores => "HIGH SCORES",
+            MenuItem::Credits => "CREDITS",
+        }
+    }
+}
+
+const MENU_ITEMS: [MenuItem; 4] = [
+    MenuItem::Play,
+    MenuItem::Settings,
+    MenuItem::HighScores,
+    MenuItem::Credits,
+];
+
+// 縦に並んだメニュー項目の位置
+struct MenuLayout {
+    y: i16,
+    spacing: i16,
+    width: i16,
+    height: i16,
+}
+
+impl MenuLayout {
+    fn rect(&self, index: usize) -> Rect {
+        Rect::new_from_x_y(
+            (WIDTH - self.width) / 2,
+            self.y + index as i16 * self.spacing,
+            self.width,
+            self.height,
+        )
+    }
+}
+
+const MAIN_MENU: MenuLayout = MenuLayout {
+    y: 190,
+    spacing: 50,
+    width: 220,
+    height: 38,
+};
+const SETTINGS_MENU: MenuLayout = MenuLayout {
+    y: 90,
+    spacing: 44,
+    width: 380,
+    height: 36,
+};
+const PAUSE_MENU: MenuLayout = MenuLayout {
+    y: 180,
+    spacing: 50,
+    width: 300,
+    height: 38,
+};
+
+// 横に並んだボタンの位置。count個を画面の中央にそろえる
+struct ButtonRow {
+    y: i16,
+    spacing: i16,
+    width: i16,
+    height: i16,
+}
+
+impl ButtonRow {
+    fn rect(&self, index: usize, count: usize) -> Rect {
+        let row_width = self.spacing * (count as i16 - 1) + self.width;
+        Rect::new_from_x_y(
+            (WIDTH - row_width) / 2 + index as i16 * self.spacing,
+            self.y,
+            self.width,
+            self.height,
+        )
+    }
+}
+
+// ゲームオーバー画面ではハイスコア表の下に並べる
+const GAME_OVER_BUTTONS: ButtonRow = ButtonRow {
+    y: 490,
+    spacing: 176,
+    width: 160,
+    height: 36,
+};
+const RETRY_BUTTONS: ButtonRow = ButtonRow {
+    y: 540,
+    ..GAME_OVER_BUTTONS
+};
+
 struct Ready;
 struct Walking;
+struct Paused {
+    // 状態が終わると一緒に捨てられて画面から消える。出せなかったときはNoneでguiを使う
+    menu: Option<PauseMenu>,
+    gui: Gui,
+}
+
+struct PauseMenu {
+    _panel: Panel,
+    resume_event: UnboundedReceiver<()>,
+    restart_event: UnboundedReceiver<()>,
+    quit_event: UnboundedReceiver<()>,
+    music_event: UnboundedReceiver<f32>,
+    sfx_event: UnboundedReceiver<f32>,
+}
+
+impl PauseMenu {
+    fn new(ui: &Element, settings: &Settings) -> Result<Self> {
+        let mut panel = Panel::new(ui, "menu")?;
+        Ok(PauseMenu {
+            resume_event: panel.button("Resume")?,
+            restart_event: panel.button("Restart")?,
+            quit_event: panel.button("Quit")?,
+            music_event: panel.slider("Music", settings.music_volume)?,
+            sfx_event: panel.slider("Sound", settings.sfx_volume)?,
+            _panel: panel,
+        })
+    }
+}
+
+fn pressed(event: &mut UnboundedReceiver<()>) -> bool {
+    matches!(event.try_next(), Ok(Some(())))
+}
+
 struct GameOver {
-    new_game_event: UnboundedReceiver<()>,
+    menu: Option<GameOverMenu>,
+    gui: Gui,
+    retry_seeds: Vec<u32>,
+    // ハイスコア表に入ったときの順位
+    rank: Option<usize>,
+    online_top_event: UnboundedReceiver<Vec<LeaderboardEntry>>,
+    online_top: Vec<LeaderboardEntry>,
 }
 
 impl GameOver {
-    fn new_game_pressed(&mut self) -> bool {
-        matches!(self.new_game_event.try_next(), Ok(Some(())))
+    fn receive_online_top(&mut self) {
+        if let Ok(Some(entries)) = self.online_top_event.try_next() {
+            self.online_top = entries;
+        }
     }
 }
 
+struct GameOverMenu {
+    _panel: Panel,
+    new_game_event: UnboundedReceiver<()>,
+    title_event: UnboundedReceiver<()>,
+    retry_event: UnboundedReceiver<usize>,
+}
+
+impl GameOverMenu {
+    fn new(ui: &Element, retry_seeds: &[HighScore]) -> Result<Self> {
+        let mut panel = Panel::new(ui, "menu row")?;
+        let new_game_event = panel.button("New Game")?;
+        let title_event = panel.button("Menu")?;
+        if !retry_seeds.is_empty() {
+            panel.label("Retry")?;
+        }
+        let rows: Vec<String> = retry_seeds
+            .iter()
+            .map(|entry| format!("{}  #{}", entry.score, entry.seed))
+            .collect();
+        Ok(GameOverMenu {
+            new_game_event,
+            title_event,
+            retry_event: panel.list(&rows)?,
+            _panel: panel,
+        })
+    }
+}
+
+enum TitleEndState {
+    Complete(WalkTheDogState<Ready>),
+    Continue(WalkTheDogState<Title>),
+}
+
 enum ReadyEndState {
     Complete(WalkTheDogState<Walking>),
     Continue(WalkTheDogState<Ready>),
@@ -240,18 +942,38 @@ impl From<ReadyEndState> for WalkTheDogStateMachine {
 
 enum WalkingEndState {
     Complete(WalkTheDogState<GameOver>),
+    Paused(WalkTheDogState<Paused>),
     Continue(WalkTheDogState<Walking>),
 }
 
+enum PausedEndState {
+    Walking(WalkTheDogState<Walking>),
+    Quit(WalkTheDogState<Title>),
+    Continue(WalkTheDogState<Paused>),
+}
+
 enum GameOverEndState {
     Complete(WalkTheDogState<Ready>),
+    Title(WalkTheDogState<Title>),
     Continue(WalkTheDogState<GameOver>),
 }
 
 
 
 pub trait Obstacle {
+    fn update(&mut self) {}
     fn check_intersection(&self, boy: &mut RedHatBoy);
+    // 取れるアイテムは触れたときに得点を返す
+    fn collect(&mut self, _boy: &RedHatBoy) -> Option<u32> {
+        None
+    }
+    fn collected(&self) -> bool {
+        false
+    }
+    // 避けきった障害物はスタイルボーナスを返す
+    fn clear(&mut self, _boy: &RedHatBoy) -> Option<u32> {
+        None
+    }
     fn draw(&self, renderer: &Renderer);
     fn draw_rect(&self, renderer: &Renderer);
     fn move_horizontally(&mut self, distance: i16);
@@ -260,11 +982,35 @@ pub trait Obstacle {
 
 pub struct Walk{
     boy: RedHatBoy,
-    backgrounds: [Image; 2],
+    background: Parallax,
     obstacles: Vec<Box<dyn Obstacle>>,
     obstacle_sheet: Rc<SpriteSheet>,
+    coin_sheet: Rc<SpriteSheet>,
+    tileset: Rc<Tileset>,
+    tiled_segments: Vec<TiledSegment>,
     stone: HtmlImageElement,
+    font: Rc<BitmapFont>,
+    audio: Audio,
+    pickup_sound: Sound,
     timeline: i16,
+    score: Score,
+    // 同じシードなら同じ順番で区間が出てくる
+    seed: u32,
+    rng: StdRng,
+    high_scores: HighScores,
+    frame: u32,
+    replay: Replay,
+    // URLが設定されていないときはランキングを使わない
+    leaderboard: Option<Rc<Leaderboard<HttpLeaderboard>>>,
+    focus_event: UnboundedReceiver<()>,
+    pointer_event: UnboundedReceiver<PointerEvent>,
+    // focus_eventとpointer_eventを送ってくるリスナー
+    listeners: Listeners,
+    control: Control,
+    context: GameContext,
+    show_hitboxes: bool,
+    settings: Settings,
+    settings_storage: Box<dyn browser::Storage>,
 }
 
 impl Walk{
@@ -273,8 +1019,7 @@ impl Walk{
     }
 
     fn generate_next_segment(&mut self){
-        let mut rng = rand::thread_rng();
-        let next_segment = rng.gen_range(0..2);
+        let next_segment = self.rng.gen_range(0..BUILT_IN_SEGMENTS + self.tiled_segments.len());
 
         let mut next_obstacles = match next_segment {
             0 => stone_and_platform(
@@ -287,9 +1032,34 @@ impl Walk{
                 self.obstacle_sheet.clone(),
                 self.timeline + OBSTACLE_BUFFER,
             ),
-            _ => vec![],
+            2 => ground_block(self.tileset.clone(), self.timeline + OBSTACLE_BUFFER),
+            3 => long_platform_over_stones(
+                self.stone.clone(),
+                self.obstacle_sheet.clone(),
+                self.timeline + OBSTACLE_BUFFER,
+            ),
+            4 => moving_platforms(
+                self.stone.clone(),
+                self.obstacle_sheet.clone(),
+                self.timeline + OBSTACLE_BUFFER,
+            ),
+            5 => pits(self.timeline + OBSTACLE_BUFFER),
+            6 => hanging_stones(self.stone.clone(), self.timeline + OBSTACLE_BUFFER),
+            7 => coins_over_stone(
+                self.stone.clone(),
+                self.coin_sheet.clone(),
+                self.timeline + OBSTACLE_BUFFER,
+            ),
+            n => self.tiled_segments[n - BUILT_IN_SEGMENTS].obstacles(
+                self.stone.clone(),
+                self.obstacle_sheet.clone(),
+                self.tileset.clone(),
+                self.timeline + OBSTACLE_BUFFER,
+            ),
         };
 
+        // 区間のあいだも地面でつなぐ。生成するフレームはtimelineが移動分だけ遅れているので前の区間に重ねておく
+        next_obstacles.push(Box::new(Ground::new(self.timeline - OBSTACLE_BUFFER, OBSTACLE_BUFFER * 2)));
         self.timeline = rightmost(&next_obstacles);
         self.obstacles.append(&mut next_obstacles);
     }
@@ -298,85 +1068,255 @@ impl Walk{
         self.boy.knocked_out()
     }
 
+    // 前回から一度でもフォーカスが外れていたらtrue
+    fn focus_lost(&mut self) -> bool {
+        let mut lost = false;
+        while let Ok(Some(())) = self.focus_event.try_next() {
+            lost = true;
+        }
+        lost
+    }
+
+    fn pointer_events(&mut self) -> Vec<PointerEvent> {
+        let mut events = Vec::new();
+        while let Ok(Some(event)) = self.pointer_event.try_next() {
+            events.push(event);
+        }
+        events
+    }
+
+    // 割り当てたキーとメニューの操作に使うキー
+    fn captured_keys(&self) -> Vec<&str> {
+        let mut keys: Vec<&str> = ACTIONS
+            .iter()
+            .map(|action| self.settings.bindings.key(*action))
+            .collect();
+        keys.extend(MENU_KEYS);
+        keys
+    }
+
+    // メニューを閉じたあとキー入力がこのゲームに届くようにする
+    fn focus(&self) {
+        if let Err(err) = self.context.focus() {
+            log!("Error focusing the game {:#?}", err);
+        }
+    }
+
+    fn pause_pressed(&self, keystate: &KeyState) -> bool {
+        keystate.was_pressed("Escape") || keystate.was_pressed(self.settings.bindings.key(Action::Pause))
+    }
+
+    // 設定を変えたらすぐに音量に反映して保存する
+    fn settings_changed(&mut self) {
+        self.audio.set_music_volume(self.settings.music_volume);
+        self.audio.set_sfx_volume(self.settings.sfx_volume);
+        if let Err(err) = self.settings.save(&mut *self.settings_storage) {
+            log!("Could not save settings {:#?}", err);
+        }
+    }
+
+    fn render_settings(&self) -> RenderSettings {
+        RenderSettings {
+            show_fps: self.settings.show_fps,
+            reduced_motion: self.settings.reduced_motion,
+        }
+    }
+
+    pub fn score(&self) -> &Score {
+        &self.score
+    }
+
+    // 送信が終わったらオンラインの上位スコアが届く
+    fn submit_run(&self, run: Run) -> UnboundedReceiver<Vec<LeaderboardEntry>> {
+        let (sender, receiver) = futures::channel::mpsc::unbounded();
+        let Some(leaderboard) = self.leaderboard.clone() else {
+            return receiver;
+        };
+        browser::spawn_local(async move {
+            if let Err(err) = leaderboard.submit(run).await {
+                log!("Could not submit run, {} waiting to be sent {:#?}", leaderboard.pending(), err);
+            }
+            match leaderboard.top(ONLINE_TOP_COUNT).await {
+                Ok(entries) => {
+                    let _ = sender.unbounded_send(entries);
+                }
+                Err(err) => {
+                    log!("Could not fetch the leaderboard {:#?}", err);
+                }
+            }
+        });
+        receiver
+    }
+
+    fn draw_gui<'a>(&'a self, queue: &mut DrawQueue<'a>, gui: &'a Gui) {
+        queue.submit(Layer::Hud, 2, move |renderer| {
+            gui.draw(renderer, &self.font);
+        });
+    }
+
+    fn draw_high_scores<'a>(&'a self, queue: &mut DrawQueue<'a>) {
+        self.draw_panel(queue, self.high_scores.table(), HIGH_SCORES_Y);
+    }
+
+    // 画面中央に半透明のパネルを敷いて文字を出す
+    fn draw_panel<'a>(&'a self, queue: &mut DrawQueue<'a>, text: String, y: i16) {
+        let (width, height) = self.font.measure(&text);
+        queue.submit(Layer::Hud, 0, move |renderer| {
+            renderer.draw_shape(
+                &Shape::RoundedRect(
+                    &Rect::new_from_x_y(
+                        (WIDTH - width) / 2 - PROMPT_PADDING,
+                        y - PROMPT_PADDING,
+                        width + PROMPT_PADDING * 2,
+                        height + PROMPT_PADDING * 2,
+                    ),
+                    PROMPT_PADDING,
+                ),
+                &PROMPT_PANEL_STYLE,
+            );
+        });
+        queue.submit(Layer::Hud, 1, move |renderer| {
+            self.font.draw_text(renderer, &text, &Point { x: (WIDTH - width) / 2, y });
+        });
+    }
+
     fn reset(walk: Self) -> Self {
+        Walk::reset_with_seed(walk, new_seed())
+    }
+
+    fn reset_with_seed(walk: Self, seed: u32) -> Self {
         let starting_obstacles = stone_and_platform(walk.stone.clone(), walk.obstacle_sheet.clone(), 0);
         let timeline = rightmost(&starting_obstacles);
 
         Walk {
             boy: RedHatBoy::reset(walk.boy),
-            backgrounds: walk.backgrounds,
+            background: walk.background,
             obstacles: starting_obstacles,
             obstacle_sheet: walk.obstacle_sheet,
+            coin_sheet: walk.coin_sheet,
+            tileset: walk.tileset,
+            tiled_segments: walk.tiled_segments,
             stone: walk.stone,
+            font: walk.font,
+            audio: walk.audio,
+            pickup_sound: walk.pickup_sound,
             timeline,
-        }
-    }
+            score: Score::default(),
+            seed,
+            rng: StdRng::seed_from_u64(seed.into()),
+            high_scores: walk.high_scores,
+            frame: 0,
+            replay: Replay::default(),
+            leaderboard: walk.leaderboard,
+            focus_event: walk.focus_event,
+            pointer_event: walk.pointer_event,
+            listeners: walk.listeners,
+            control: walk.control,
+            context: walk.context,
+            show_hitboxes: walk.show_hitboxes,
+            settings: walk.settings,
+            settings_storage: walk.settings_storage,
+        }
+    }
+
+    fn draw<'a>(&'a self, queue: &mut DrawQueue<'a>){
+        queue.submit(Layer::Background, 0, move |renderer| self.background.draw(renderer));
+        queue.submit(Layer::Actors, 0, move |renderer| self.boy.draw(renderer));
 
-    fn draw(&self, renderer: &Renderer){
-        self.backgrounds.iter().for_each(|background| {
-            background.draw(renderer);
+        self.obstacles.iter().for_each(|obstacle| {
+            queue.submit(Layer::World, 0, move |renderer| obstacle.draw(renderer));
         });
-        self.boy.draw(renderer);
 
-        self.obstacles.iter().for_each(|obstacle| {
-            obstacle.draw(renderer);
+        let hud = format!(
+            "SCORE {}\n{}M  COINS {}",
+            self.score.total(),
+            self.score.meters(),
+            self.score.coins(),
+        );
+        queue.submit(Layer::Hud, 0, move |renderer| {
+            self.font.draw_text(renderer, &hud, &HUD_POSITION);
         });
+
+        if self.show_hitboxes {
+            queue.submit(Layer::Foreground, 0, move |renderer| {
+                self.boy.draw_rect(renderer);
+                self.obstacles.iter().for_each(|obstacle| obstacle.draw_rect(renderer));
+            });
+        }
     }
 }
 
+fn new_seed() -> u32 {
+    rand::thread_rng().gen()
+}
+
 impl WalkTheDog{
     // pub fn new() -> Self{
     //     WalkTheDog::Loading
     // }
-    pub fn new() -> Self {
+    pub fn new(config: GameConfig, control: Control) -> Self {
         WalkTheDog {
             machine: None,
+            config,
+            control,
         }
     }
 }
 
-const LOW_PLATFORM: i16 = 420;
-const FIRST_PLATFORM: i16 = 375;
 #[async_trait(?Send)]
 impl Game for WalkTheDog{
-    async fn initialize(&self) -> Result<Box<dyn Game>>{
+    async fn initialize(&self, context: &GameContext) -> Result<Box<dyn Game>>{
         match self.machine {
             None => {
                 let sheet = browser::fetch_json("rhb.json").await?;
-                let background = engine::load_image("BG.png").await?;
+                let background = Parallax::load(
+                    browser::fetch_json("parallax.json").await?.into_serde::<ParallaxConfig>()?,
+                    WIDTH,
+                ).await?;
                 let stone = engine::load_image("Stone.png").await?;
 
-                let background_width = background.width() as i16;
-
                 let tiles = browser::fetch_json("tiles.json").await?;
                 let sprite_sheet = Rc::new(SpriteSheet::new(
                     tiles.into_serde::<Sheet>()?,
                     engine::load_image("tiles.png").await?,
                 ));
 
+                let tileset = Rc::new(Tileset::new(
+                    sprite_sheet.clone(),
+                    browser::fetch_json("tileset.json").await?.into_serde::<TilesetConfig>()?,
+                ));
+
+                let mut tiled_segments = vec![];
+                for path in TILED_SEGMENTS {
+                    let map = browser::fetch_json(path).await?.into_serde::<TiledMap>()?;
+                    tiled_segments.push(
+                        TiledSegment::new(map).map_err(|err| anyhow!("{}: {}", path, err))?,
+                    );
+                }
+
                 let starting_obstacles = stone_and_platform(stone.clone(), sprite_sheet.clone(), 0);
                 let timeline = rightmost(&starting_obstacles);
 
-                let platform = Platform::new(
-                    sprite_sheet.clone(),
-                    Point {x: FIRST_PLATFORM, y: LOW_PLATFORM},
-                    &["13.png", "14.png", "15.png"],
-                    &[
-                        Rect::new_from_x_y(0, 0, 60, 54),
-                        Rect::new_from_x_y(60, 0, 384 - (60*2), 93),
-                        Rect::new_from_x_y(384 - 60, 0, 60, 54),
-                    ]
+                let font = BitmapFont::new(
+                    browser::fetch_json("font.json").await?.into_serde::<FontDescriptor>()?,
+                    engine::load_image("font.png").await?,
                 );
 
                 let audio = Audio::new()?;
                 let sound = audio.load_sound("SFX_Jump_23.mp3").await?;
+                let pickup_sound = audio.load_sound("SFX_Coin.wav").await?;
+                let coin_sheet = Rc::new(SpriteSheet::new(
+                    browser::fetch_json("coin.json").await?.into_serde::<Sheet>()?,
+                    engine::load_image("coin.png").await?,
+                ));
                 let background_music = audio.load_sound("background_song.mp3").await?;
                 audio.play_looping_sound(&background_music)?;
 
                 let rhb = RedHatBoy::new(
                     sheet.into_serde::<Sheet>()?,
+                    Rc::new(browser::fetch_json("rhb_hitboxes.json").await?.into_serde::<Hitboxes>()?),
                     engine::load_image("rhb.png").await?,
-                    audio,
+                    audio.clone(),
                     sound,
                 );
                 // Ok(Box::new(WalkTheDog::Loaded(Walk {
@@ -394,20 +1334,54 @@ impl Game for WalkTheDog{
                 //     stone,
                 //     timeline,
                 // })))
+                let mut settings_storage = browser::storage(&context.storage_namespace);
+                let settings = Settings::load(&mut *settings_storage);
+                audio.set_music_volume(settings.music_volume);
+                audio.set_sfx_volume(settings.sfx_volume);
+
+                let mut listeners = Listeners::default();
+                let seed = self.config.seed.unwrap_or_else(new_seed);
+                let leaderboard_url = self.config.leaderboard_url.as_deref().or(LEADERBOARD_URL);
                 let machine = WalkTheDogStateMachine::new(Walk {
                     boy: rhb,
-                    backgrounds: [
-                        Image::new(background.clone(), Point {x:0 , y:0}),
-                        Image::new(background, Point {x:background_width , y:0}),
-                    ],
+                    background,
                     obstacles: starting_obstacles,
                     obstacle_sheet: sprite_sheet,
+                    coin_sheet,
+                    tileset,
+                    tiled_segments,
                     stone,
+                    font: Rc::new(font),
+                    audio,
+                    pickup_sound,
                     timeline,
+                    score: Score::default(),
+                    seed,
+                    rng: StdRng::seed_from_u64(seed.into()),
+                    high_scores: HighScores::load(browser::storage(&context.storage_namespace)),
+                    frame: 0,
+                    replay: Replay::default(),
+                    leaderboard: leaderboard_url.map(|url| {
+                        Rc::new(Leaderboard::new(
+                            HttpLeaderboard::new(url),
+                            browser::storage(&context.storage_namespace),
+                        ))
+                    }),
+                    focus_event: engine::add_blur_handler(&context.input_target, &mut listeners)?,
+                    pointer_event: engine::add_pointer_handler(&context.canvas, &mut listeners)?,
+                    listeners,
+                    control: self.control.clone(),
+                    context: context.clone(),
+                    // URLに?debugを付けると当たり判定を表示する
+                    show_hitboxes: browser::debug_mode(),
+                    settings,
+                    settings_storage,
                     }
                 );
                 Ok(Box::new(WalkTheDog {
                     machine: Some(machine),
+                    config: self.config.clone(),
+                    control: self.control.clone(),
                 }))
             }
             Some(_) => Err(anyhow!("Error: Game is already initialized!")),
@@ -416,8 +1390,13 @@ impl Game for WalkTheDog{
 
     fn update(&mut self, keystate: &KeyState){
         //if let WalkTheDog::Loaded(walk) = self{
-        if let Some(machine) = self.machine.take(){
-            self.machine.replace(machine.update(keystate));
+        if let Some(mut machine) = self.machine.take(){
+            while let Some(command) = self.control.next_command() {
+                machine = machine.command(command);
+            }
+            let machine = machine.update(keystate);
+            self.control.set_score(machine.walk().score().total());
+            self.machine.replace(machine);
 
             // if keystate.is_pressed("ArrowRight"){
             //     walk.boy.run_right();
@@ -456,59 +1435,356 @@ impl Game for WalkTheDog{
         assert!(self.machine.is_some());
     }
 
+    fn captured_keys(&self) -> Vec<&str>{
+        match &self.machine {
+            Some(machine) => machine.walk().captured_keys(),
+            None => Vec::new(),
+        }
+    }
+
     fn draw(&self, renderer: &Renderer){
-        renderer.clear(&Rect::new_from_x_y(0, 0, 600, 600));
+        renderer.clear(&Rect::new_from_x_y(0, 0, WIDTH, HEIGHT));
 
         if let Some(machine) = &self.machine {
             machine.draw(renderer);
         }
 
-        // if let WalkTheDog::Loaded(walk) = self{
-        //     //walk.background.draw(renderer);
-        //     walk.backgrounds.iter().for_each(|background|{
-        //         background.draw(renderer);
-        //     });
-        //     walk.boy.draw(renderer);
-        //     walk.obstacles.iter().for_each(|obstacle| {
-        //         obstacle.draw(renderer);
-        //     });
+        // if let WalkTheDog::Loaded(walk) = self{
+        //     //walk.background.draw(renderer);
+        //     walk.backgrounds.iter().for_each(|background|{
+        //         background.draw(renderer);
+        //     });
+        //     walk.boy.draw(renderer);
+        //     walk.obstacles.iter().for_each(|obstacle| {
+        //         obstacle.draw(renderer);
+        //     });
+
+        //     // 矩形を描画
+        //     walk.boy.draw_rect(renderer);
+        //     walk.obstacles.iter().for_each(|obstacle| {
+        //         obstacle.draw_rect(renderer);
+        //     });
+        // }
+    }
+}
+
+pub struct Barrier{
+    image: Image,
+    cleared: bool,
+}
+
+impl Barrier {
+    pub fn new(image: Image) -> Self {
+        Barrier {
+            image,
+            cleared: false,
+        }
+    }
+}
+
+impl Obstacle for Barrier {
+    fn check_intersection(&self, boy: &mut RedHatBoy) {
+        if boy.bounding_box().intersects(self.image.bounding_box()) {
+            boy.knock_out();
+        }
+    }
+
+    fn clear(&mut self, boy: &RedHatBoy) -> Option<u32> {
+        jump_bonus(&mut self.cleared, self.image.right(), boy.bounding_box().x(), boy.jumping())
+    }
+
+    fn draw(&self, renderer: &Renderer) {
+        self.image.draw(renderer);
+    }
+
+    fn draw_rect(&self, renderer: &Renderer) {
+        self.image.draw_rect(renderer);
+    }
+
+    fn move_horizontally(&mut self, x: i16) {
+        self.image.move_horizontally(x);
+    }
+
+    fn right(&self) -> i16 {
+        self.image.right()
+    }
+}
+
+
+// 障害物が男の子の後ろへ抜けたら一度だけボーナスを返す
+fn clear_once(cleared: &mut bool, right: i16, boy_x: i16, bonus: u32) -> Option<u32> {
+    if !*cleared && right < boy_x {
+        *cleared = true;
+        Some(bonus)
+    } else {
+        None
+    }
+}
+
+// 飛び越えたときだけボーナス。走ったまま抜けたときは何も出さない
+fn jump_bonus(cleared: &mut bool, right: i16, boy_x: i16, jumping: bool) -> Option<u32> {
+    clear_once(cleared, right, boy_x, JUMP_BONUS).filter(|_| jumping)
+}
+
+pub struct Platform {
+    sheet: Rc<SpriteSheet>,
+    bounding_boxes: Vec<Rect>,
+    sprites: Vec<Cell>,
+    position: Point,
+    // 直前のフレームで動いた量。乗っている少年を一緒に運ぶ
+    velocity: Point,
+}
+
+impl Platform {
+    pub fn new(
+        sheet: Rc<SpriteSheet>,
+        position: Point,
+        sprite_names: &[&str],
+        bounding_boxes: &[Rect],
+    ) -> Self {
+        let sprites = sprite_names
+            .iter()
+            .filter_map(|sprite_name| sheet.cell(sprite_name).cloned())
+            .collect();
+    
+        let bounding_boxes = bounding_boxes
+            .iter()
+            .map(|bounding_box| {
+                Rect::new_from_x_y(
+                    bounding_box.x() + position.x,
+                    bounding_box.y() + position.y,
+                    bounding_box.width,
+                    bounding_box.height,
+                )
+            })
+            .collect();
+
+        Platform {
+            sheet,
+            position,
+            sprites,
+            bounding_boxes,
+            velocity: Point::default(),
+        }
+    }
+
+    // 左端、繰り返す中央、右端のタイルから任意の長さの足場を作る
+    pub fn from_tiles(
+        sheet: Rc<SpriteSheet>,
+        position: Point,
+        tiles: &PlatformTiles,
+        middle_count: usize,
+    ) -> Self {
+        let mut sprite_names = vec![tiles.left];
+        sprite_names.extend(std::iter::repeat(tiles.middle).take(middle_count));
+        sprite_names.push(tiles.right);
+
+        let (width, height) = sprite_names
+            .iter()
+            .filter_map(|sprite_name| sheet.cell(sprite_name))
+            .fold((0, 0), |(width, height), cell| {
+                (width + cell.frame.w, height.max(cell.frame.h))
+            });
+        let bounding_boxes = platform_bounding_boxes(width, height, tiles.edge_width, tiles.edge_height);
+
+        Platform::new(sheet, position, &sprite_names, &bounding_boxes)
+    }
+
+    fn bounding_boxes(&self) -> &[Rect] {
+        &self.bounding_boxes
+    }
+
+    fn move_by(&mut self, distance: Point) {
+        self.position.x += distance.x;
+        self.position.y += distance.y;
+        self.bounding_boxes.iter_mut().for_each(|bounding_box| {
+            bounding_box.position.x += distance.x;
+            bounding_box.position.y += distance.y;
+        });
+    }
+
+    fn destination_box(&self) -> Rect {
+        let width = self.sprites.iter().map(|sprite| sprite.frame.w).sum();
+        let height = self.sprites.iter().map(|sprite| sprite.frame.h).max().unwrap_or(0);
+
+        Rect::new(self.position, width, height)
+    }
+}
+
+pub struct PlatformTiles<'a> {
+    pub left: &'a str,
+    pub middle: &'a str,
+    pub right: &'a str,
+    // 端のタイルは丸まっているので当たり判定を小さくする
+    pub edge_width: i16,
+    pub edge_height: i16,
+}
+
+fn platform_bounding_boxes(width: i16, height: i16, edge_width: i16, edge_height: i16) -> Vec<Rect> {
+    let edge_width = edge_width.min(width / 2);
+    vec![
+        Rect::new_from_x_y(0, 0, edge_width, edge_height),
+        Rect::new_from_x_y(edge_width, 0, width - edge_width * 2, height),
+        Rect::new_from_x_y(width - edge_width, 0, edge_width, edge_height),
+    ]
+}
+
+impl Obstacle for Platform{
+    fn draw(&self, renderer: &Renderer) {
+        let mut x = 0;
+        self.sprites.iter().for_each(|sprite| {
+            self.sheet.draw(
+                renderer,
+                &Rect::new_from_x_y(
+                    sprite.frame.x,
+                    sprite.frame.y,
+                    sprite.frame.w,
+                    sprite.frame.h,
+                ),
+                &Rect::new_from_x_y(
+                    self.position.x + x,
+                    self.position.y,
+                    sprite.frame.w,
+                    sprite.frame.h,
+                ),
+            );
+            x += sprite.frame.w;
+        });
+    }
+
+    fn draw_rect(&self, renderer: &Renderer) {
+        for bounding_box in self.bounding_boxes() {
+            renderer.draw_rect(bounding_box);
+        }
+    }
+
+    fn check_intersection(&self, boy: &mut RedHatBoy) {
+        if let Some(box_to_land_on) = self
+            .bounding_boxes()
+            .iter()
+            .find(|bounding_box| boy.bounding_box().intersects(bounding_box))
+        {
+            // 足場が上に動いていても、少年が足場より速く落ちていれば着地する
+            if boy.velocity_y() > self.velocity.y && boy.pos_y() < self.position.y {
+                boy.land_on(box_to_land_on.y(), self.velocity);
+            } else {
+                boy.knock_out();
+            }
+        }
+    }
+    
+    fn move_horizontally(&mut self, x: i16) {
+        self.move_by(Point { x, y: 0 });
+    }
+
+    fn right(&self) -> i16 {
+        self.destination_box().right()
+    }
+}
+
+// 区間が敷く地面。地面の無いところが穴になる
+pub struct Ground {
+    bounds: Rect,
+}
+
+impl Ground {
+    pub fn new(x: i16, width: i16) -> Self {
+        Ground {
+            bounds: Rect::new_from_x_y(x, HEIGHT - GROUND_SURFACE_HEIGHT, width, GROUND_SURFACE_HEIGHT),
+        }
+    }
+
+    fn supports(&self, bounding_box: &Rect) -> bool {
+        let center = bounding_box.x() + bounding_box.width / 2;
+        self.bounds.x() <= center && center < self.bounds.right()
+    }
+
+    // 床より下に落ちたら、向こう岸の地面の壁にぶつかる
+    fn walls_in(&self, bounding_box: &Rect) -> bool {
+        self.bounds.intersects(bounding_box)
+    }
+}
+
+impl Obstacle for Ground {
+    fn check_intersection(&self, boy: &mut RedHatBoy) {
+        let bounding_box = boy.bounding_box();
+        if boy.below_floor() {
+            if self.walls_in(&bounding_box) {
+                boy.knock_out();
+            }
+        } else if self.supports(&bounding_box) {
+            boy.set_on_ground(true);
+        }
+    }
+
+    fn draw(&self, renderer: &Renderer) {
+        renderer.draw_shape(&Shape::Rect(&self.bounds), &GROUND_STYLE);
+    }
+
+    fn draw_rect(&self, renderer: &Renderer) {
+        renderer.draw_rect(&self.bounds);
+    }
+
+    fn move_horizontally(&mut self, x: i16) {
+        self.bounds.set_x(self.bounds.x() + x);
+    }
 
-        //     // 矩形を描画
-        //     walk.boy.draw_rect(renderer);
-        //     walk.obstacles.iter().for_each(|obstacle| {
-        //         obstacle.draw_rect(renderer);
-        //     });
-        // }
+    fn right(&self) -> i16 {
+        self.bounds.right()
     }
 }
 
-pub struct Barrier{
+// 天井から吊り下がった障害物。スライディングでくぐる
+pub struct Hanging {
     image: Image,
+    rope: Rect,
+    cleared: bool,
 }
 
-impl Barrier {
+impl Hanging {
     pub fn new(image: Image) -> Self {
-        Barrier { image }
+        let rope = Hanging::rope(image.bounding_box());
+        Hanging {
+            image,
+            rope,
+            cleared: false,
+        }
+    }
+
+    // 石の真ん中から画面の上端まで
+    fn rope(stone: &Rect) -> Rect {
+        Rect::new_from_x_y(stone.x() + stone.width / 2 - ROPE_WIDTH / 2, 0, ROPE_WIDTH, stone.y())
+    }
+
+    fn hits(stone: &Rect, rope: &Rect, bounding_box: &Rect) -> bool {
+        bounding_box.intersects(stone) || bounding_box.intersects(rope)
     }
 }
 
-impl Obstacle for Barrier {
+impl Obstacle for Hanging {
     fn check_intersection(&self, boy: &mut RedHatBoy) {
-        if boy.bounding_box().intersects(self.image.bounding_box()) {
+        if Hanging::hits(self.image.bounding_box(), &self.rope, &boy.bounding_box()) {
             boy.knock_out();
         }
     }
 
+    fn clear(&mut self, boy: &RedHatBoy) -> Option<u32> {
+        clear_once(&mut self.cleared, self.image.right(), boy.bounding_box().x(), SLIDE_BONUS)
+    }
+
     fn draw(&self, renderer: &Renderer) {
+        renderer.draw_shape(&Shape::Rect(&self.rope), &ROPE_STYLE);
         self.image.draw(renderer);
     }
 
     fn draw_rect(&self, renderer: &Renderer) {
         self.image.draw_rect(renderer);
+        renderer.draw_rect(&self.rope);
     }
 
     fn move_horizontally(&mut self, x: i16) {
         self.image.move_horizontally(x);
+        self.rope.set_x(self.rope.x() + x);
     }
 
     fn right(&self) -> i16 {
@@ -516,124 +1792,165 @@ impl Obstacle for Barrier {
     }
 }
 
-
-pub struct Platform {
+// 触れると取れるコイン。回転アニメーションする
+pub struct Coin {
     sheet: Rc<SpriteSheet>,
-    bounding_boxes: Vec<Rect>,
-    sprites: Vec<Cell>,
     position: Point,
+    frame: u8,
+    collected: bool,
 }
 
-impl Platform {
-    pub fn new(
-        sheet: Rc<SpriteSheet>,
-        position: Point,
-        sprite_names: &[&str],
-        bounding_boxes: &[Rect],
-    ) -> Self {
-        let sprites = sprite_names
-            .iter()
-            .filter_map(|sprite_name| sheet.cell(sprite_name).cloned())
-            .collect();
-    
-        let bounding_boxes = bounding_boxes
-            .iter()
-            .map(|bounding_box| {
-                Rect::new_from_x_y(
-                    bounding_box.x() + position.x,
-                    bounding_box.y() + position.y,
-                    bounding_box.width,
-                    bounding_box.height,
-                )
-            })
-            .collect();
-
-        Platform {
+impl Coin {
+    pub fn new(sheet: Rc<SpriteSheet>, position: Point) -> Self {
+        Coin {
             sheet,
             position,
-            sprites,
-            bounding_boxes,
+            frame: 0,
+            collected: false,
         }
     }
 
-    fn bounding_boxes(&self) -> &Vec<Rect> {
-        &self.bounding_boxes
+    fn bounding_box(&self) -> Rect {
+        Rect::new_from_x_y(self.position.x, self.position.y, COIN_SIZE, COIN_SIZE)
+    }
+}
+
+impl Obstacle for Coin {
+    fn update(&mut self) {
+        self.frame = (self.frame + 1) % (COIN_FRAMES * COIN_FRAME_TICKS);
     }
 
-    fn destination_box(&self) -> Rect {
-        let platform = self
-            .sheet
-            .cell("13.png")
-            .expect("13.png does not exist");
+    fn check_intersection(&self, _boy: &mut RedHatBoy) {}
 
-        Rect::new_from_x_y(
-            self.position.x.into(),
-            self.position.y.into(),
-            (platform.frame.w * 3) as i16,
-            platform.frame.h as i16,
-        )
+    fn collect(&mut self, boy: &RedHatBoy) -> Option<u32> {
+        if !self.collected && boy.bounding_box().intersects(&self.bounding_box()) {
+            self.collected = true;
+            Some(COIN_POINTS)
+        } else {
+            None
+        }
+    }
+
+    fn collected(&self) -> bool {
+        self.collected
     }
-}
 
-impl Obstacle for Platform{
     fn draw(&self, renderer: &Renderer) {
-        let mut x = 0;
-        self.sprites.iter().for_each(|sprite| {
+        // 動きを減らす設定なら回さない
+        let frame = if renderer.settings().reduced_motion { 0 } else { self.frame / COIN_FRAME_TICKS };
+        let frame_name = format!("Coin ({}).png", frame + 1);
+        if let Some(cell) = self.sheet.cell(&frame_name) {
             self.sheet.draw(
                 renderer,
-                &Rect::new_from_x_y(
-                    sprite.frame.x,
-                    sprite.frame.y,
-                    sprite.frame.w,
-                    sprite.frame.h,
-                ),
-                &Rect::new_from_x_y(
-                    self.position.x + x,
-                    self.position.y,
-                    sprite.frame.w,
-                    sprite.frame.h,
-                ),
+                &Rect::new_from_x_y(cell.frame.x, cell.frame.y, cell.frame.w, cell.frame.h),
+                &self.bounding_box(),
             );
-            x += sprite.frame.w;
-        });
+        }
     }
 
     fn draw_rect(&self, renderer: &Renderer) {
-        for bounding_box in self.bounding_boxes() {
-            renderer.draw_rect(&bounding_box);
+        renderer.draw_rect(&self.bounding_box());
+    }
+
+    fn move_horizontally(&mut self, x: i16) {
+        self.position.x += x;
+    }
+
+    fn right(&self) -> i16 {
+        self.bounding_box().right()
+    }
+}
+
+pub struct MovingPlatform {
+    platform: Platform,
+    motion: Motion,
+}
+
+impl MovingPlatform {
+    pub fn new(platform: Platform, motion: Motion) -> Self {
+        MovingPlatform { platform, motion }
+    }
+}
+
+impl Obstacle for MovingPlatform {
+    fn update(&mut self) {
+        let distance = self.motion.advance();
+        self.platform.move_by(distance);
+        self.platform.velocity = distance;
+    }
+
+    fn check_intersection(&self, boy: &mut RedHatBoy) {
+        self.platform.check_intersection(boy);
+    }
+
+    fn draw(&self, renderer: &Renderer) {
+        self.platform.draw(renderer);
+    }
+
+    // 足場の当たり判定に加えて、動く道すじと今の位置を描く
+    fn draw_rect(&self, renderer: &Renderer) {
+        self.platform.draw_rect(renderer);
+        let offset = self.motion.offset();
+        let start = Point {
+            x: self.platform.position.x - offset.x,
+            y: self.platform.position.y - offset.y,
+        };
+        let (trail, closed) = self.motion.trail();
+        let trail: Vec<Point> = trail
+            .iter()
+            .map(|point| Point { x: start.x + point.x, y: start.y + point.y })
+            .collect();
+        match trail.as_slice() {
+            [from, to] if !closed => renderer.draw_debug_shape(&Shape::Line(*from, *to)),
+            points => renderer.draw_debug_shape(&Shape::Path(points, closed)),
         }
+        renderer.draw_debug_shape(&Shape::Circle(self.platform.position, MOTION_MARKER_RADIUS));
+    }
+
+    fn move_horizontally(&mut self, x: i16) {
+        self.platform.move_horizontally(x);
+    }
+
+    fn right(&self) -> i16 {
+        self.platform.right()
     }
+}
 
+impl Obstacle for Tilemap {
     fn check_intersection(&self, boy: &mut RedHatBoy) {
         if let Some(box_to_land_on) = self
-            .bounding_boxes()
+            .solid_boxes()
             .iter()
             .find(|bounding_box| boy.bounding_box().intersects(bounding_box))
         {
-            if boy.velocity_y() > 0 && boy.pos_y() < self.position.y {
-                boy.land_on(box_to_land_on.y());
+            if boy.velocity_y() > 0 && boy.pos_y() < box_to_land_on.y() {
+                boy.land_on(box_to_land_on.y(), Point::default());
             } else {
                 boy.knock_out();
             }
         }
     }
-    
+
+    fn draw(&self, renderer: &Renderer) {
+        Tilemap::draw(self, renderer);
+    }
+
+    fn draw_rect(&self, renderer: &Renderer) {
+        for bounding_box in self.solid_boxes() {
+            renderer.draw_rect(&bounding_box);
+        }
+    }
+
     fn move_horizontally(&mut self, x: i16) {
-        self.position.x += x;
-        self.bounding_boxes.iter_mut().for_each(|bounding_box| {
-            bounding_box.set_x(bounding_box.x() + x);
-        });
+        Tilemap::move_horizontally(self, x);
     }
 
     fn right(&self) -> i16 {
-        self.bounding_boxes()
-            .last()
-            .unwrap_or(&Rect::default())
-            .right()
+        Tilemap::right(self)
     }
 }
 
-fn rightmost(obstacle_list: &Vec<Box<dyn Obstacle>>) -> i16 {
+pub fn rightmost(obstacle_list: &[Box<dyn Obstacle>]) -> i16 {
     obstacle_list
         .iter()
         .map(|obstacle| obstacle.right())
@@ -642,17 +1959,19 @@ fn rightmost(obstacle_list: &Vec<Box<dyn Obstacle>>) -> i16 {
 }
 
 
-struct RedHatBoy{
+pub struct RedHatBoy{
     state_machine: RedHatBoyStateMachine,
     sprite_sheet: Sheet,
+    hitboxes: Rc<Hitboxes>,
     image: HtmlImageElement,
 }
 
 impl RedHatBoy{
-    fn new(sheet: Sheet, image: HtmlImageElement, audio: Audio, sound: Sound) -> Self{
+    fn new(sheet: Sheet, hitboxes: Rc<Hitboxes>, image: HtmlImageElement, audio: Audio, sound: Sound) -> Self{
         RedHatBoy{
             state_machine: RedHatBoyStateMachine::Idle(RedHatBoyState::new(audio, sound)),
             sprite_sheet: sheet,
+            hitboxes,
             image,
         }
     }
@@ -660,47 +1979,65 @@ impl RedHatBoy{
     fn draw(&self, renderer: &Renderer){
         let sprite = self.current_sprite().expect("Cell not found");
 
-        renderer.draw_image(
+        renderer.draw_image_with_options(
             &self.image,
             &Rect::new_from_x_y(
-                sprite.frame.x as i16,
-                sprite.frame.y as i16,
-                sprite.frame.w as i16,
-                sprite.frame.h as i16,
+                sprite.frame.x,
+                sprite.frame.y,
+                sprite.frame.w,
+                sprite.frame.h,
             ),
             &Rect::new_from_x_y(
-                (self.state_machine.context().position.x + sprite.sprite_source_size.x as i16).into(),
-                (self.state_machine.context().position.y + sprite.sprite_source_size.y as i16).into(),
-                sprite.frame.w as i16,
-                sprite.frame.h as i16,
+                self.state_machine.context().position.x + sprite.sprite_source_size.x,
+                self.state_machine.context().position.y + sprite.sprite_source_size.y,
+                sprite.frame.w,
+                sprite.frame.h,
             ),
+            &self.draw_options(renderer.settings().reduced_motion),
         );
     }
 
+    // ぶつかった直後は赤く点滅させる。動きを減らす設定なら点滅しない
+    fn draw_options(&self, reduced_motion: bool) -> DrawOptions {
+        match &self.state_machine {
+            RedHatBoyStateMachine::Falling(state)
+                if !reduced_motion && (state.context().frame / HIT_FLASH_FRAMES) % 2 == 0 =>
+            {
+                DrawOptions {
+                    tint: Some(HIT_FLASH_TINT),
+                    ..DrawOptions::default()
+                }
+            }
+            _ => DrawOptions::default(),
+        }
+    }
+
     fn draw_rect(&self, renderer: &Renderer){
         renderer.draw_rect(&self.bounding_box());
     }
 
+    // 当たり判定はデータで決めた枠を使い、無ければ画像の大きさをそのまま使う
     fn bounding_box(&self) -> Rect {
-        const X_OFFSET: i16 = 18;
-        const Y_OFFSET: i16 = 14;
-        const WIDTH_OFFSET: i16 = 28;
-        Rect::new_from_x_y(
-            self.destination_box().x() + X_OFFSET,
-            self.destination_box().y() + Y_OFFSET,
-            self.destination_box().width - WIDTH_OFFSET,
-            self.destination_box().height - Y_OFFSET,
-        )
+        let position = self.state_machine.context().position;
+        match self.hitboxes.get(self.state_machine.frame_name(), &self.frame_name()) {
+            Some(hitbox) => Rect::new_from_x_y(
+                position.x + hitbox.x(),
+                position.y + hitbox.y(),
+                hitbox.width,
+                hitbox.height,
+            ),
+            None => self.destination_box(),
+        }
     }
 
     fn destination_box(&self) -> Rect {
         let sprite = self.current_sprite().expect("Cell not found");
 
         Rect::new_from_x_y(
-            (self.state_machine.context().position.x + sprite.sprite_source_size.x as i16).into(),
-            (self.state_machine.context().position.y + sprite.sprite_source_size.y as i16).into(),
-            sprite.frame.w as i16,
-            sprite.frame.h as i16,
+            self.state_machine.context().position.x + sprite.sprite_source_size.x,
+            self.state_machine.context().position.y + sprite.sprite_source_size.y,
+            sprite.frame.w,
+            sprite.frame.h,
         )
     }
 
@@ -715,6 +2052,7 @@ impl RedHatBoy{
     fn reset(boy: Self) -> Self{
         RedHatBoy::new(
             boy.sprite_sheet,
+            boy.hitboxes,
             boy.image,
             boy.state_machine.context().audio.clone(),
             boy.state_machine.context().jump_sound.clone(),
@@ -725,6 +2063,22 @@ impl RedHatBoy{
         self.state_machine.knocked_out()
     }
 
+    fn jumping(&self) -> bool{
+        self.state_machine.jumping()
+    }
+
+    fn set_on_ground(&mut self, on_ground: bool){
+        self.state_machine = self.state_machine.clone().set_on_ground(on_ground);
+    }
+
+    fn below_floor(&self) -> bool{
+        self.state_machine.context().below_floor()
+    }
+
+    fn fell_off_screen(&self) -> bool{
+        self.state_machine.context().position.y > HEIGHT
+    }
+
     fn pos_y(&self) -> i16{
         self.state_machine.context().position.y
     }
@@ -754,11 +2108,18 @@ impl RedHatBoy{
     }
 
     fn jump(&mut self){
+        // 穴に落ち始めたらもう跳べない
+        if self.below_floor() {
+            return;
+        }
         self.state_machine = self.state_machine.clone().transition(Event::Jump);
     }
 
-    fn land_on(&mut self, position: i16){
-        self.state_machine = self.state_machine.clone().transition(Event::Land(position));
+    fn land_on(&mut self, position: i16, platform_velocity: Point){
+        self.state_machine = self
+            .state_machine
+            .clone()
+            .transition(Event::Land(position, platform_velocity));
     }
 
     fn knock_out(&mut self){
@@ -788,7 +2149,7 @@ pub enum Event{
     Update,
     Jump,
     KnockOut,
-    Land(i16),
+    Land(i16, Point),
 }
 
 impl RedHatBoyStateMachine{
@@ -796,6 +2157,7 @@ impl RedHatBoyStateMachine{
         match(self.clone(), event){
             (RedHatBoyStateMachine::Idle(state), Event::Run) => state.run().into(),
             (RedHatBoyStateMachine::Running(state), Event::Slide) => state.slide().into(),
+            (RedHatBoyStateMachine::Sliding(state), Event::Slide) => state.keep_sliding().into(),
             (RedHatBoyStateMachine::Idle(state), Event::Update) => state.update().into(),
             (RedHatBoyStateMachine::Running(state), Event::Update) => state.update().into(),
             (RedHatBoyStateMachine::Sliding(state), Event::Update) => state.update().into(),
@@ -805,9 +2167,15 @@ impl RedHatBoyStateMachine{
             (RedHatBoyStateMachine::Sliding(state), Event::KnockOut) => state.knock_out().into(),
             (RedHatBoyStateMachine::Jumping(state), Event::KnockOut) => state.knock_out().into(),
             (RedHatBoyStateMachine::Falling(state), Event::Update) => state.update().into(),
-            (RedHatBoyStateMachine::Jumping(state), Event::Land(position)) => state.land_on(position).into(),
-            (RedHatBoyStateMachine::Running(state), Event::Land(position)) => state.land_on(position).into(),
-            (RedHatBoyStateMachine::Sliding(state), Event::Land(position)) => state.land_on(position).into(),
+            (RedHatBoyStateMachine::Jumping(state), Event::Land(position, velocity)) => {
+                state.land_on(position, velocity).into()
+            }
+            (RedHatBoyStateMachine::Running(state), Event::Land(position, velocity)) => {
+                state.land_on(position, velocity).into()
+            }
+            (RedHatBoyStateMachine::Sliding(state), Event::Land(position, velocity)) => {
+                state.land_on(position, velocity).into()
+            }
             _ => self,
         }
     }
@@ -825,12 +2193,12 @@ impl RedHatBoyStateMachine{
 
     fn context(&self) -> &RedHatBoyContext{
         match self{
-            RedHatBoyStateMachine::Idle(state) => &state.context(),
-            RedHatBoyStateMachine::Running(state) => &state.context(),
-            RedHatBoyStateMachine::Sliding(state) => &state.context(),
-            RedHatBoyStateMachine::Jumping(state) => &state.context(),
-            RedHatBoyStateMachine::Falling(state) => &state.context(),
-            RedHatBoyStateMachine::KnockedOut(state) => &state.context(),
+            RedHatBoyStateMachine::Idle(state) => state.context(),
+            RedHatBoyStateMachine::Running(state) => state.context(),
+            RedHatBoyStateMachine::Sliding(state) => state.context(),
+            RedHatBoyStateMachine::Jumping(state) => state.context(),
+            RedHatBoyStateMachine::Falling(state) => state.context(),
+            RedHatBoyStateMachine::KnockedOut(state) => state.context(),
         }
     }
 
@@ -838,6 +2206,22 @@ impl RedHatBoyStateMachine{
         matches!(self, RedHatBoyStateMachine::KnockedOut(_))
     }
 
+    // Fallingはぶつかって倒れていく状態なので、飛び越えたことにはしない
+    fn jumping(&self) -> bool {
+        matches!(self, RedHatBoyStateMachine::Jumping(_))
+    }
+
+    fn set_on_ground(self, on_ground: bool) -> Self{
+        match self{
+            RedHatBoyStateMachine::Idle(state) => state.set_on_ground(on_ground).into(),
+            RedHatBoyStateMachine::Running(state) => state.set_on_ground(on_ground).into(),
+            RedHatBoyStateMachine::Sliding(state) => state.set_on_ground(on_ground).into(),
+            RedHatBoyStateMachine::Jumping(state) => state.set_on_ground(on_ground).into(),
+            RedHatBoyStateMachine::Falling(state) => state.set_on_ground(on_ground).into(),
+            RedHatBoyStateMachine::KnockedOut(state) => state.set_on_ground(on_ground).into(),
+        }
+    }
+
     fn update(self) -> Self{
         self.transition(Event::Update)
     }
@@ -904,17 +2288,21 @@ mod red_hat_boy_states{
     use crate::engine::Point;
     use super::{HEIGHT, Audio, Sound};
 
-    const FLOOR: i16 = 479;
+    pub(super) const FLOOR: i16 = 479;
     const PLAYER_HEIGHT: i16 = HEIGHT - FLOOR;
     const GRAVITY: i16 = 1;
     const TERMINAL_VELOCITY: i16 = 20;
     const STARTING_POINT: i16 = -20;
     const RUNNING_SPEED: i16 = 4;
     const JUMP_SPEED: i16 = -25;
+    // 動く足場に運ばれて元の位置からずれてよい幅と、足場を離れたあと戻る速さ
+    const MAX_CARRY: i16 = 120;
+    const CARRY_RETURN_SPEED: i16 = 2;
 
     const IDLE_FRAMES: u8 = 29;
     const RUNNING_FRAMES: u8 = 23;
     const SLIDING_FRAMES: u8 = 14;
+    const SLIDE_HOLD_FRAME: u8 = SLIDING_FRAMES - 2;
     const JUMPING_FRAMES: u8 = 35;
     const FALLING_FRAMES: u8 = 29;
     const IDLE_FRAME_NAME: &str = "Idle";
@@ -923,6 +2311,56 @@ mod red_hat_boy_states{
     const JUMP_FRAME_NAME: &str = "Jump";
     const FALL_FRAME_NAME: &str = "Dead";
 
+    // ジャンプしてからframes後に男の子がどれだけ動いているか
+    pub fn jump_offset(frames: i16) -> Point {
+        let mut velocity = JUMP_SPEED;
+        let mut rise = 0;
+        for _ in 0..frames {
+            if velocity < TERMINAL_VELOCITY {
+                velocity += GRAVITY;
+            }
+            rise += velocity;
+        }
+        Point {
+            x: RUNNING_SPEED * frames,
+            y: rise,
+        }
+    }
+
+    // 床で止まるかどうか。一度床より下に落ちたら、もう床には戻らない
+    pub fn stops_at_floor(previous_y: i16, y: i16, on_ground: bool) -> bool{
+        previous_y <= FLOOR && y >= FLOOR && on_ground
+    }
+
+    // 動く足場に運ばれて元の位置から横にずれた分
+    #[derive(Debug, Default, Copy, Clone)]
+    pub struct Carry{
+        offset: i16,
+        riding: bool,
+    }
+
+    impl Carry{
+        pub fn offset(&self) -> i16{
+            self.offset
+        }
+
+        // 足場が横に動いた分だけずらす。画面の外まで運ばれないように抑える
+        pub fn ride(&mut self, distance: i16){
+            if distance != 0{
+                self.offset = (self.offset + distance).clamp(-MAX_CARRY, MAX_CARRY);
+                self.riding = true;
+            }
+        }
+
+        // 前のフレームで運ばれていなければ、少しずつ元の位置へ戻す
+        pub fn update(&mut self){
+            if !self.riding{
+                self.offset -= self.offset.clamp(-CARRY_RETURN_SPEED, CARRY_RETURN_SPEED);
+            }
+            self.riding = false;
+        }
+    }
+
     #[derive(Clone)]
     pub struct RedHatBoyState<S>{
         context: RedHatBoyContext,
@@ -936,6 +2374,9 @@ mod red_hat_boy_states{
         pub velocity: Point,
         pub audio: Audio,
         pub jump_sound: Sound,
+        // 足元に地面があるかどうか。地面が無ければ床で止まらない
+        pub on_ground: bool,
+        pub carry: Carry,
     }
 
     impl RedHatBoyContext{
@@ -951,14 +2392,21 @@ mod red_hat_boy_states{
             }
 
             //self.position.x += self.velocity.x;
+            self.carry.update();
+            self.position.x = STARTING_POINT + self.carry.offset();
+            let previous_y = self.position.y;
             self.position.y += self.velocity.y;
-            if self.position.y > FLOOR{
+            if stops_at_floor(previous_y, self.position.y, self.on_ground){
                 self.position.y = FLOOR;
             }
 
             self
         }
 
+        pub fn below_floor(&self) -> bool{
+            self.position.y > FLOOR
+        }
+
         fn set_vertical_velocity(mut self, y: i16) -> Self{
             self.velocity.y = y;
             self
@@ -985,6 +2433,13 @@ mod red_hat_boy_states{
             self
         }
 
+        // 動く足場に乗っているときは横方向の移動を引き継ぐ
+        fn carry(mut self, platform_velocity: Point) -> Self{
+            self.carry.ride(platform_velocity.x);
+            self.position.x = STARTING_POINT + self.carry.offset();
+            self
+        }
+
         fn play_jump_sound(self) -> Self{
             if let Err(err) = self.audio.play_sound(&self.jump_sound) {
                 log!("Error playing jump sound: {:#?}", err);
@@ -1015,6 +2470,11 @@ mod red_hat_boy_states{
         pub fn context(&self) -> &RedHatBoyContext{
             &self.context
         }
+
+        pub fn set_on_ground(mut self, on_ground: bool) -> Self{
+            self.context.on_ground = on_ground;
+            self
+        }
     }
 
     impl RedHatBoyState<Idle>{
@@ -1026,6 +2486,8 @@ mod red_hat_boy_states{
                     velocity: Point{x: 0, y: 0},
                     audio,
                     jump_sound,
+                    on_ground: true,
+                    carry: Carry::default(),
                 },
                 _state: Idle {},
             }
@@ -1083,9 +2545,9 @@ mod red_hat_boy_states{
             }
         }
 
-        pub fn land_on(self, position: i16) -> RedHatBoyState<Running>{
+        pub fn land_on(self, position: i16, platform_velocity: Point) -> RedHatBoyState<Running>{
             RedHatBoyState{
-                context: self.context.set_on(position),
+                context: self.context.set_on(position).carry(platform_velocity),
                 _state: Running {},
             }
         }
@@ -1105,6 +2567,14 @@ mod red_hat_boy_states{
             }
         }
 
+        // 下キーを押し続けている間は最後の姿勢のまま滑り続ける
+        pub fn keep_sliding(mut self) -> Self{
+            if self.context.frame > SLIDE_HOLD_FRAME{
+                self.context.frame = SLIDE_HOLD_FRAME;
+            }
+            self
+        }
+
         pub fn stand(self) -> RedHatBoyState<Running>{
             RedHatBoyState{
                 context: self.context.reset_frame(),
@@ -1119,9 +2589,9 @@ mod red_hat_boy_states{
             }
         }
 
-        pub fn land_on(self, position: i16) -> RedHatBoyState<Sliding>{
+        pub fn land_on(self, position: i16, platform_velocity: Point) -> RedHatBoyState<Sliding>{
             RedHatBoyState{
-                context: self.context.set_on(position),
+                context: self.context.set_on(position).carry(platform_velocity),
                 _state: Sliding {},
             }
         }
@@ -1133,25 +2603,19 @@ mod red_hat_boy_states{
         }
 
         pub fn update(mut self) -> JumpingEndState {
+            let previous_y = self.context.position.y;
             self.context = self.context.update(JUMPING_FRAMES);
-            if self.context.position.y >= FLOOR{
-                JumpingEndState::Complete(self.land_on(HEIGHT.into()))
+            if stops_at_floor(previous_y, self.context.position.y, self.context.on_ground){
+                JumpingEndState::Complete(self.land_on(HEIGHT, Point::default()))
             } else {
                 JumpingEndState::Jumping(self)
             }
         }
 
-        pub fn land(self) -> RedHatBoyState<Running>{
-            RedHatBoyState{
-                context: self.context.reset_frame(),
-                _state: Running {},
-            }
-        }
-
-        pub fn land_on(self, position: i16) -> RedHatBoyState<Running>{
+        pub fn land_on(self, position: i16, platform_velocity: Point) -> RedHatBoyState<Running>{
             log!("Landed on {}", position);
             RedHatBoyState{
-                context: self.context.reset_frame().set_on(position),
+                context: self.context.reset_frame().set_on(position).carry(platform_velocity),
                 _state: Running,
             }
         }
@@ -1206,4 +2670,150 @@ mod red_hat_boy_states{
         KnockedOut(RedHatBoyState<KnockedOut>),
         Falling(RedHatBoyState<Falling>),
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::segments::STONE_AT_HEAD_HEIGHT;
+
+    #[test]
+    fn platform_bounding_boxes_follow_the_tile_layout() {
+        let boxes = platform_bounding_boxes(640, 93, 60, 54);
+        let layout: Vec<(i16, i16, i16, i16)> = boxes
+            .iter()
+            .map(|bounding_box| (bounding_box.x(), bounding_box.y(), bounding_box.width, bounding_box.height))
+            .collect();
+
+        assert_eq!(layout, vec![(0, 0, 60, 54), (60, 0, 520, 93), (580, 0, 60, 54)]);
+    }
+
+    #[test]
+    fn only_a_sliding_boy_fits_under_a_hanging_stone() {
+        let hitboxes: Hitboxes = serde_json::from_str(include_str!("../static/rhb_hitboxes.json")).unwrap();
+        let boy_box = |animation: &str, y: i16| {
+            let hitbox = hitboxes.get(animation, "").unwrap();
+            Rect::new_from_x_y(hitbox.x(), y + hitbox.y(), hitbox.width, hitbox.height)
+        };
+        let stone = Rect::new_from_x_y(60, STONE_AT_HEAD_HEIGHT, 90, 54);
+        let rope = Hanging::rope(&stone);
+
+        assert!(Hanging::hits(&stone, &rope, &boy_box("Run", FLOOR)));
+        assert!(!Hanging::hits(&stone, &rope, &boy_box("Slide", FLOOR)));
+        // 飛び越えようとしてもロープに引っかかる
+        assert!(Hanging::hits(&stone, &rope, &boy_box("Jump", 300)));
+    }
+
+    #[test]
+    fn coming_down_past_the_far_edge_of_a_pit_does_not_snap_back_to_the_floor() {
+        // 穴で床より下まで落ちた次のフレーム、足元はもう向こう岸の地面
+        assert!(!stops_at_floor(FLOOR + 10, FLOOR + 20, true));
+        assert!(stops_at_floor(FLOOR - 10, FLOOR + 5, true));
+        assert!(!stops_at_floor(FLOOR - 10, FLOOR + 5, false));
+    }
+
+    #[test]
+    fn ground_supports_the_boy_only_while_his_center_is_over_it() {
+        let ground = Ground::new(100, 200);
+
+        assert!(ground.supports(&Rect::new_from_x_y(60, FLOOR, 80, 100)));
+        assert!(!ground.supports(&Rect::new_from_x_y(40, FLOOR, 80, 100)));
+        assert!(!ground.supports(&Rect::new_from_x_y(270, FLOOR, 80, 100)));
+    }
+
+    #[test]
+    fn a_boy_below_the_floor_hits_the_wall_of_the_far_ground() {
+        let ground = Ground::new(300, 200);
+
+        assert!(ground.walls_in(&Rect::new_from_x_y(260, HEIGHT - 100, 80, 120)));
+        assert!(!ground.walls_in(&Rect::new_from_x_y(200, HEIGHT - 100, 80, 120)));
+    }
+
+    #[test]
+    fn jump_offset_peaks_at_the_top_of_the_arc() {
+        let peak = jump_offset(24);
+
+        assert_eq!((peak.x, peak.y), (96, -300));
+        assert!(jump_offset(30).y > peak.y);
+    }
+
+    #[test]
+    fn jumping_over_a_barrier_earns_the_bonus_once() {
+        let mut cleared = false;
+
+        assert_eq!(jump_bonus(&mut cleared, 100, 90, true), None);
+        assert_eq!(jump_bonus(&mut cleared, 100, 110, true), Some(JUMP_BONUS));
+        assert_eq!(jump_bonus(&mut cleared, 100, 120, true), None);
+    }
+
+    #[test]
+    fn running_past_a_barrier_earns_nothing() {
+        let mut cleared = false;
+
+        assert_eq!(jump_bonus(&mut cleared, 100, 110, false), None);
+        // 抜けたあとに跳んでも後からボーナスは出ない
+        assert_eq!(jump_bonus(&mut cleared, 100, 120, true), None);
+    }
+
+    #[test]
+    fn riding_a_moving_platform_shifts_the_boy_until_he_jumps_off() {
+        let mut carry = Carry::default();
+
+        // 着地して足場に10フレーム運ばれる
+        for _ in 0..10 {
+            carry.update();
+            carry.ride(3);
+        }
+        assert_eq!(carry.offset(), 30);
+
+        // ジャンプで足場を離れると、次のフレームから元の位置へ戻っていく
+        for _ in 0..15 {
+            carry.update();
+        }
+        assert_eq!(carry.offset(), 2);
+        carry.update();
+        assert_eq!(carry.offset(), 0);
+    }
+
+    #[test]
+    fn a_long_ride_does_not_carry_the_boy_off_screen() {
+        let mut carry = Carry::default();
+
+        for _ in 0..1000 {
+            carry.update();
+            carry.ride(5);
+        }
+
+        assert_eq!(carry.offset(), 120);
+    }
+
+    #[test]
+    fn button_rows_are_centred_and_stay_on_screen() {
+        let retries: Vec<(i16, i16)> = (0..3)
+            .map(|index| RETRY_BUTTONS.rect(index, 3))
+            .map(|rect| (rect.x(), rect.right()))
+            .collect();
+
+        assert_eq!(retries, vec![(44, 204), (220, 380), (396, 556)]);
+        assert_eq!(GAME_OVER_BUTTONS.rect(0, 2).x(), WIDTH - GAME_OVER_BUTTONS.rect(1, 2).right());
+        assert!(RETRY_BUTTONS.rect(0, 1).bottom() <= HEIGHT);
+    }
+
+    #[test]
+    fn clicking_a_main_menu_item_activates_only_that_item() {
+        let second = MAIN_MENU.rect(1);
+        let mut gui = Gui::default();
+        gui.begin(GuiInput {
+            click: Some(Point { x: second.x() + 1, y: second.y() + 1 }),
+            ..GuiInput::default()
+        });
+
+        let activated: Vec<bool> = MENU_ITEMS
+            .iter()
+            .enumerate()
+            .map(|(index, item)| gui.button(MAIN_MENU.rect(index), item.label()))
+            .collect();
+
+        assert_eq!(activated, vec![false, true, false, false]);
+    }
+}
@@ -0,0 +1,132 @@
+/* ローカルのハイスコア表。Storageに JSON で保存する */
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::browser::Storage;
+
+const HIGH_SCORES_KEY: &str = "walk_the_dog.high_scores";
+const MAX_ENTRIES: usize = 10;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct HighScore {
+    pub score: u32,
+    pub date: String,
+    pub seed: u32,
+}
+
+pub struct HighScores {
+    entries: Vec<HighScore>,
+    storage: Box<dyn Storage>,
+}
+
+impl HighScores {
+    // 保存された表が壊れていたら空の表から始める
+    pub fn load(mut storage: Box<dyn Storage>) -> Self {
+        let entries = match storage.get(HIGH_SCORES_KEY) {
+            Ok(Some(json)) => serde_json::from_str(&json).unwrap_or_else(|err| {
+                log!("Could not parse high scores {:#?}", err);
+                Vec::new()
+            }),
+            Ok(None) => Vec::new(),
+            Err(err) => {
+                log!("Could not load high scores {:#?}", err);
+                Vec::new()
+            }
+        };
+        HighScores { entries, storage }
+    }
+
+    // 表に入ったら順位(1始まり)を返す
+    pub fn record(&mut self, entry: HighScore) -> Option<usize> {
+        // スタート直後に倒れた0点は残さない
+        if entry.score == 0 {
+            return None;
+        }
+        let index = self
+            .entries
+            .iter()
+            .position(|high_score| entry.score > high_score.score)
+            .unwrap_or(self.entries.len());
+        if index >= MAX_ENTRIES {
+            return None;
+        }
+
+        self.entries.insert(index, entry);
+        self.entries.truncate(MAX_ENTRIES);
+        if let Err(err) = self.save() {
+            log!("Could not save high scores {:#?}", err);
+        }
+        Some(index + 1)
+    }
+
+    pub fn entries(&self) -> &[HighScore] {
+        &self.entries
+    }
+
+    fn save(&mut self) -> Result<()> {
+        let json = serde_json::to_string(&self.entries)?;
+        self.storage.set(HIGH_SCORES_KEY, &json)
+    }
+
+    // 画面に出す表
+    pub fn table(&self) -> String {
+        if self.entries().is_empty() {
+            return "HIGH SCORES\nNO SCORES YET".to_string();
+        }
+        let rows: Vec<String> = self
+            .entries()
+            .iter()
+            .enumerate()
+            .map(|(index, entry)| {
+                format!("{:>2}. {:>6}  {}  #{}", index + 1, entry.score, entry.date, entry.seed)
+            })
+            .collect();
+        format!("HIGH SCORES\n{}", rows.join("\n"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::browser::MemoryStorage;
+
+    fn entry(score: u32) -> HighScore {
+        HighScore {
+            score,
+            date: "2024-01-01".to_string(),
+            seed: score,
+        }
+    }
+
+    #[test]
+    fn record_keeps_the_top_ten_in_order() {
+        let mut high_scores = HighScores::load(Box::<MemoryStorage>::default());
+        (1..=10).for_each(|score| {
+            high_scores.record(entry(score * 10));
+        });
+
+        assert_eq!(high_scores.record(entry(5)), None);
+        assert_eq!(high_scores.record(entry(55)), Some(6));
+        assert_eq!(high_scores.entries().len(), 10);
+        assert_eq!(high_scores.entries()[0].score, 100);
+        assert_eq!(high_scores.entries()[9].score, 20);
+    }
+
+    #[test]
+    fn a_zero_score_is_not_recorded() {
+        let mut high_scores = HighScores::load(Box::<MemoryStorage>::default());
+
+        assert_eq!(high_scores.record(entry(0)), None);
+        assert!(high_scores.entries().is_empty());
+    }
+
+    #[test]
+    fn scores_survive_a_reload() {
+        let mut storage = MemoryStorage::default();
+        storage.set(HIGH_SCORES_KEY, &serde_json::to_string(&vec![entry(30)]).unwrap()).unwrap();
+
+        let high_scores = HighScores::load(Box::new(storage));
+
+        assert_eq!(high_scores.entries(), &[entry(30)]);
+    }
+}
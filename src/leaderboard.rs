@@ -0,0 +1,342 @@
+/* オンラインランキングとのやりとり。送れなかった記録はStorageに溜めておいて後で送る */
+use anyhow::Result;
+use async_trait::async_trait;
+use gloo_utils::format::JsValueSerdeExt;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+
+use crate::browser::{self, Storage};
+
+const PENDING_RUNS_KEY: &str = "walk_the_dog.pending_runs";
+// リプレイごと溜めるのでlocalStorageの容量を使い切らないように数を抑える
+const MAX_PENDING_RUNS: usize = 10;
+
+// 押した操作と離した操作("jump"や"slide")をフレーム番号つきで記録する。シードと合わせれば走りを再現できる
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Replay {
+    inputs: Vec<ReplayInput>,
+    #[serde(skip)]
+    held: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ReplayInput {
+    pub frame: u32,
+    pub key: String,
+    pub pressed: bool,
+}
+
+impl Replay {
+    pub fn record(&mut self, frame: u32, key: &str, pressed: bool) {
+        let was_pressed = self.held.iter().any(|held| held == key);
+        if pressed == was_pressed {
+            return;
+        }
+
+        if pressed {
+            self.held.push(key.to_string());
+        } else {
+            self.held.retain(|held| held != key);
+        }
+        self.inputs.push(ReplayInput {
+            frame,
+            key: key.to_string(),
+            pressed,
+        });
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Run {
+    pub score: u32,
+    pub seed: u32,
+    pub date: String,
+    pub replay: Replay,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct LeaderboardEntry {
+    pub score: u32,
+    pub seed: u32,
+    pub date: String,
+}
+
+#[async_trait(?Send)]
+pub trait LeaderboardServer {
+    async fn submit(&self, run: &Run) -> Result<()>;
+    async fn top(&self, count: usize) -> Result<Vec<LeaderboardEntry>>;
+}
+
+// POST {base_url}/runs で記録を送り、GET {base_url}/runs/top?count=n で上位を取る
+pub struct HttpLeaderboard {
+    base_url: String,
+}
+
+impl HttpLeaderboard {
+    pub fn new(base_url: &str) -> Self {
+        HttpLeaderboard {
+            base_url: base_url.trim_end_matches('/').to_string(),
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl LeaderboardServer for HttpLeaderboard {
+    async fn submit(&self, run: &Run) -> Result<()> {
+        browser::post_json(&format!("{}/runs", self.base_url), run).await?;
+        Ok(())
+    }
+
+    async fn top(&self, count: usize) -> Result<Vec<LeaderboardEntry>> {
+        let entries = browser::fetch_json(&format!("{}/runs/top?count={}", self.base_url, count)).await?;
+        Ok(entries.into_serde::<Vec<LeaderboardEntry>>()?)
+    }
+}
+
+pub struct Leaderboard<S: LeaderboardServer> {
+    server: S,
+    pending: RefCell<Vec<Run>>,
+    storage: RefCell<Box<dyn Storage>>,
+}
+
+impl<S: LeaderboardServer> Leaderboard<S> {
+    pub fn new(server: S, mut storage: Box<dyn Storage>) -> Self {
+        let pending = match storage.get(PENDING_RUNS_KEY) {
+            Ok(Some(json)) => serde_json::from_str(&json).unwrap_or_else(|err| {
+                log!("Could not parse pending runs {:#?}", err);
+                Vec::new()
+            }),
+            Ok(None) => Vec::new(),
+            Err(err) => {
+                log!("Could not load pending runs {:#?}", err);
+                Vec::new()
+            }
+        };
+        Leaderboard {
+            server,
+            pending: RefCell::new(pending),
+            storage: RefCell::new(storage),
+        }
+    }
+
+    // 送れなかったときは記録をキューに残してエラーを返す
+    pub async fn submit(&self, run: Run) -> Result<()> {
+        self.pending.borrow_mut().push(run);
+        self.save_pending();
+        self.flush().await
+    }
+
+    pub async fn flush(&self) -> Result<()> {
+        // await中に借用を持たないように一度取り出す
+        let runs = self.pending.take();
+        for (index, run) in runs.iter().enumerate() {
+            if let Err(err) = self.server.submit(run).await {
+                let mut unsent = runs[index..].to_vec();
+                unsent.append(&mut self.pending.borrow_mut());
+                self.pending.replace(unsent);
+                self.save_pending();
+                return Err(err);
+            }
+        }
+        self.save_pending();
+        Ok(())
+    }
+
+    pub async fn top(&self, count: usize) -> Result<Vec<LeaderboardEntry>> {
+        self.server.top(count).await
+    }
+
+    pub fn pending(&self) -> usize {
+        self.pending.borrow().len()
+    }
+
+    // 溜まりすぎたら古い記録から捨てる
+    fn save_pending(&self) {
+        {
+            let mut pending = self.pending.borrow_mut();
+            let excess = pending.len().saturating_sub(MAX_PENDING_RUNS);
+            pending.drain(..excess);
+        }
+        let result = serde_json::to_string(&*self.pending.borrow())
+            .map_err(anyhow::Error::from)
+            .and_then(|json| self.storage.borrow_mut().set(PENDING_RUNS_KEY, &json));
+        if let Err(err) = result {
+            log!("Could not save pending runs {:#?}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::browser::MemoryStorage;
+    use anyhow::anyhow;
+    use futures::executor::block_on;
+    use std::cell::Cell;
+
+    // js/leaderboard-server.js と同じ振る舞いをするテスト用のサーバー
+    #[derive(Default)]
+    struct LocalServer {
+        runs: RefCell<Vec<Run>>,
+        offline: Cell<bool>,
+    }
+
+    #[async_trait(?Send)]
+    impl LeaderboardServer for &LocalServer {
+        async fn submit(&self, run: &Run) -> Result<()> {
+            if self.offline.get() {
+                return Err(anyhow!("Server is offline"));
+            }
+            self.runs.borrow_mut().push(run.clone());
+            Ok(())
+        }
+
+        async fn top(&self, count: usize) -> Result<Vec<LeaderboardEntry>> {
+            if self.offline.get() {
+                return Err(anyhow!("Server is offline"));
+            }
+            let mut entries: Vec<LeaderboardEntry> = self
+                .runs
+                .borrow()
+                .iter()
+                .map(|run| LeaderboardEntry {
+                    score: run.score,
+                    seed: run.seed,
+                    date: run.date.clone(),
+                })
+                .collect();
+            entries.sort_by(|a, b| b.score.cmp(&a.score));
+            entries.truncate(count);
+            Ok(entries)
+        }
+    }
+
+    fn run(score: u32) -> Run {
+        Run {
+            score,
+            seed: score,
+            date: "2024-01-01".to_string(),
+            replay: Replay::default(),
+        }
+    }
+
+    #[test]
+    fn submitted_runs_come_back_in_the_top_entries() {
+        let server = LocalServer::default();
+        let leaderboard = Leaderboard::new(&server, Box::<MemoryStorage>::default());
+
+        block_on(leaderboard.submit(run(20))).unwrap();
+        block_on(leaderboard.submit(run(50))).unwrap();
+        let top = block_on(leaderboard.top(1)).unwrap();
+
+        assert_eq!(top.iter().map(|entry| entry.score).collect::<Vec<_>>(), vec![50]);
+        assert_eq!(leaderboard.pending(), 0);
+    }
+
+    #[test]
+    fn runs_are_queued_while_offline_and_sent_later() {
+        let server = LocalServer::default();
+        server.offline.set(true);
+        let leaderboard = Leaderboard::new(&server, Box::<MemoryStorage>::default());
+
+        assert!(block_on(leaderboard.submit(run(10))).is_err());
+        assert!(block_on(leaderboard.submit(run(30))).is_err());
+        assert_eq!(leaderboard.pending(), 2);
+
+        server.offline.set(false);
+        block_on(leaderboard.flush()).unwrap();
+
+        assert_eq!(leaderboard.pending(), 0);
+        assert_eq!(server.runs.borrow().iter().map(|run| run.score).collect::<Vec<_>>(), vec![10, 30]);
+    }
+
+    #[test]
+    fn only_the_newest_pending_runs_are_kept() {
+        let server = LocalServer::default();
+        server.offline.set(true);
+        let leaderboard = Leaderboard::new(&server, Box::<MemoryStorage>::default());
+
+        for score in 0..MAX_PENDING_RUNS as u32 + 3 {
+            assert!(block_on(leaderboard.submit(run(score))).is_err());
+        }
+        assert_eq!(leaderboard.pending(), MAX_PENDING_RUNS);
+
+        server.offline.set(false);
+        block_on(leaderboard.flush()).unwrap();
+
+        let sent: Vec<u32> = server.runs.borrow().iter().map(|run| run.score).collect();
+        assert_eq!(sent, (3..MAX_PENDING_RUNS as u32 + 3).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn pending_runs_are_restored_from_storage() {
+        let mut storage = MemoryStorage::default();
+        storage
+            .set(PENDING_RUNS_KEY, &serde_json::to_string(&vec![run(40)]).unwrap())
+            .unwrap();
+        let server = LocalServer::default();
+
+        let leaderboard = Leaderboard::new(&server, Box::new(storage));
+
+        assert_eq!(leaderboard.pending(), 1);
+    }
+
+    #[test]
+    fn replay_records_only_presses_and_releases() {
+        let mut replay = Replay::default();
+        replay.record(1, "Space", true);
+        replay.record(2, "Space", true);
+        replay.record(3, "Space", false);
+        replay.record(4, "ArrowDown", false);
+
+        let inputs: Vec<(u32, bool)> = replay.inputs.iter().map(|input| (input.frame, input.pressed)).collect();
+        assert_eq!(inputs, vec![(1, true), (3, false)]);
+    }
+}
+
+// js/leaderboard-server.js を相手にHttpLeaderboardを動かす。npm test がサーバーを立ててから走らせる
+#[cfg(all(test, target_arch = "wasm32"))]
+mod http_tests {
+    use super::*;
+    use crate::browser::MemoryStorage;
+    use wasm_bindgen_test::{wasm_bindgen_test, wasm_bindgen_test_configure};
+
+    wasm_bindgen_test_configure!(run_in_browser);
+
+    // npm run leaderboard と重ならないポート
+    const TEST_SERVER_URL: &str = "http://localhost:8788";
+
+    fn run(score: u32) -> Run {
+        let mut replay = Replay::default();
+        replay.record(1, "jump", true);
+        replay.record(9, "jump", false);
+        Run {
+            score,
+            seed: score,
+            date: "2024-01-01".to_string(),
+            replay,
+        }
+    }
+
+    #[wasm_bindgen_test]
+    async fn submitted_runs_come_back_from_the_local_server() {
+        let leaderboard = Leaderboard::new(HttpLeaderboard::new(TEST_SERVER_URL), Box::<MemoryStorage>::default());
+
+        leaderboard.submit(run(20)).await.unwrap();
+        leaderboard.submit(run(50)).await.unwrap();
+        let top = leaderboard.top(1).await.unwrap();
+
+        assert_eq!(top, vec![LeaderboardEntry { score: 50, seed: 50, date: "2024-01-01".to_string() }]);
+        assert_eq!(leaderboard.pending(), 0);
+    }
+
+    #[wasm_bindgen_test]
+    async fn rejected_runs_stay_queued() {
+        // 404が返るので送れなかった扱いになる
+        let server = HttpLeaderboard::new(&format!("{}/missing", TEST_SERVER_URL));
+        let leaderboard = Leaderboard::new(server, Box::<MemoryStorage>::default());
+
+        assert!(leaderboard.submit(run(30)).await.is_err());
+        assert_eq!(leaderboard.pending(), 1);
+    }
+}
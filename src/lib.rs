@@ -1,13 +1,25 @@
+use control::{Command, Control, GameConfig, GameEvent};
+use futures::{channel::mpsc::UnboundedReceiver, StreamExt};
+use engine::{GameContext, GameLoopHandle};
 use game::WalkTheDog;
+use gloo_utils::format::JsValueSerdeExt;
+use std::{cell::RefCell, future::Future, rc::Rc};
 use wasm_bindgen::prelude::*;
 use anyhow::{Result};
 
 #[macro_use]
 mod browser;
+mod control;
 mod engine;
 mod game;
+mod high_scores;
+mod leaderboard;
+mod score;
 mod segments;
+mod settings;
 mod sound;
+mod tiled;
+mod ui;
 
 
 // When the `wee_alloc` feature is enabled, this uses `wee_alloc` as the global
@@ -20,17 +32,141 @@ static ALLOC: wee_alloc::WeeAlloc = wee_alloc::WeeAlloc::INIT;
 
 
 // This is like the `main` function, except for JavaScript.
+// ゲームはページ側でWalkTheDogHandleを作ってstartしたときに始まる
 #[wasm_bindgen(start)]
 pub fn main_js() -> Result<(), JsValue> {
     console_error_panic_hook::set_once();
 
-    browser::spawn_local(async move{
-        let game = WalkTheDog::new();
+    Ok(())
+}
 
-        engine::GameLoop::start(game)
-            .await
-            .expect("Failed to start game");
-    });
+// ページ側(JavaScript)からゲームを動かしたり様子を見たりするための窓口
+#[wasm_bindgen]
+pub struct WalkTheDogHandle {
+    control: Control,
+    events: Option<UnboundedReceiver<GameEvent>>,
+    callbacks: Rc<RefCell<Vec<js_sys::Function>>>,
+    // 動いているゲームループ。stop()やfree()で捨てるとループが止まる
+    game_loop: Rc<RefCell<Option<GameLoopHandle>>>,
+}
 
-    Ok(())
+impl Default for WalkTheDogHandle {
+    fn default() -> Self {
+        let (control, events) = Control::new();
+        WalkTheDogHandle {
+            control,
+            events: Some(events),
+            callbacks: Rc::new(RefCell::new(Vec::new())),
+            game_loop: Rc::new(RefCell::new(None)),
+        }
+    }
+}
+
+impl WalkTheDogHandle {
+    // 設定とページの要素を確かめて、ゲームループを始めるFutureを返す
+    fn prepare_start(
+        &mut self,
+        config: JsValue,
+    ) -> Result<impl Future<Output = Result<JsValue, JsValue>>, JsValue> {
+        let config = if config.is_undefined() || config.is_null() {
+            GameConfig::default()
+        } else {
+            config
+                .into_serde::<GameConfig>()
+                .map_err(|err| JsValue::from_str(&format!("Invalid config {}", err)))?
+        };
+        let context = GameContext::from_ids(
+            &config.canvas_id,
+            &config.ui_id,
+            config.input_id.as_deref(),
+            config.storage_namespace.as_deref(),
+        )
+        .map_err(|err| JsValue::from_str(&format!("{:#}", err)))?;
+        // 同じページの他のゲームからフォーカスを奪わないよう、頼まれたときだけ移す
+        if config.autofocus {
+            if let Err(err) = context.focus() {
+                log!("Could not focus the game {:#?}", err);
+            }
+        }
+        let mut events = self
+            .events
+            .take()
+            .ok_or_else(|| JsValue::from_str("Game is already started"))?;
+
+        let callbacks = Rc::clone(&self.callbacks);
+        browser::spawn_local(async move {
+            while let Some(event) = events.next().await {
+                let value = match JsValue::from_serde(&event) {
+                    Ok(value) => value,
+                    Err(err) => {
+                        log!("Could not convert game event {:#?}", err);
+                        continue;
+                    }
+                };
+                // コールバックの中からon_eventが呼ばれても借用が重ならないように写しを使う
+                let callbacks = callbacks.borrow().clone();
+                for callback in callbacks {
+                    if let Err(err) = callback.call1(&JsValue::NULL, &value) {
+                        log!("Game event callback failed {:#?}", err);
+                    }
+                }
+            }
+        });
+
+        let control = self.control.clone();
+        let game_loop = Rc::clone(&self.game_loop);
+        Ok(async move {
+            let game = WalkTheDog::new(config, control);
+
+            let handle = engine::GameLoop::start(game, context)
+                .await
+                .map_err(|err| JsValue::from_str(&format!("Failed to start game {:#}", err)))?;
+            *game_loop.borrow_mut() = Some(handle);
+            Ok(JsValue::UNDEFINED)
+        })
+    }
+}
+
+#[wasm_bindgen]
+impl WalkTheDogHandle {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> WalkTheDogHandle {
+        WalkTheDogHandle::default()
+    }
+
+    // config は { seed?, leaderboardUrl?, canvasId?, uiId?, inputId?, storageNamespace?, autofocus? }。省略してもよい
+    // 返すPromiseはゲームループが動き出したら解決し、始められなかったらエラーで棄却される
+    pub fn start(&mut self, config: JsValue) -> js_sys::Promise {
+        match self.prepare_start(config) {
+            Ok(started) => browser::future_to_promise(started),
+            Err(err) => js_sys::Promise::reject(&err),
+        }
+    }
+
+    // ゲームループを止めて、ループが持っているリスナーやゲームを解放する
+    pub fn stop(&self) {
+        self.game_loop.borrow_mut().take();
+    }
+
+    pub fn pause(&self) {
+        self.control.send(Command::Pause);
+    }
+
+    pub fn resume(&self) {
+        self.control.send(Command::Resume);
+    }
+
+    // seedを省略するとランダムなシードで始め直す
+    pub fn restart(&self, seed: Option<u32>) {
+        self.control.send(Command::Restart(seed));
+    }
+
+    pub fn get_score(&self) -> u32 {
+        self.control.score()
+    }
+
+    // callbackには { type: "started" | "paused" | "resumed" | "gameOver", ... } が渡される
+    pub fn on_event(&self, callback: js_sys::Function) {
+        self.callbacks.borrow_mut().push(callback);
+    }
 }
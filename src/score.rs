@@ -0,0 +1,80 @@
+/* 走った距離・コイン・スタイルボーナスから得点を計算する */
+
+// 画面上のピクセルをメートルに直す
+const PIXELS_PER_METER: u32 = 40;
+const POINTS_PER_METER: u32 = 1;
+
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+pub struct Score {
+    distance: u32,
+    coins: u32,
+    coin_points: u32,
+    style: u32,
+}
+
+impl Score {
+    pub fn run(&mut self, distance: i16) {
+        self.distance += distance.max(0) as u32;
+    }
+
+    pub fn collect(&mut self, points: u32) {
+        self.coins += 1;
+        self.coin_points += points;
+    }
+
+    pub fn bonus(&mut self, points: u32) {
+        self.style += points;
+    }
+
+    pub fn meters(&self) -> u32 {
+        self.distance / PIXELS_PER_METER
+    }
+
+    pub fn coins(&self) -> u32 {
+        self.coins
+    }
+
+    pub fn total(&self) -> u32 {
+        self.meters() * POINTS_PER_METER + self.coin_points + self.style
+    }
+
+    // ゲームオーバー画面に出す内訳
+    pub fn breakdown(&self) -> String {
+        format!(
+            "DISTANCE {}M  {}\nCOINS x{}  {}\nSTYLE  {}\nTOTAL  {}",
+            self.meters(),
+            self.meters() * POINTS_PER_METER,
+            self.coins,
+            self.coin_points,
+            self.style,
+            self.total(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn total_adds_distance_coins_and_style() {
+        let mut score = Score::default();
+        (0..100).for_each(|_| score.run(4));
+        score.collect(10);
+        score.collect(10);
+        score.bonus(25);
+
+        assert_eq!(score.meters(), 10);
+        assert_eq!(score.coins(), 2);
+        assert_eq!(score.total(), 10 + 20 + 25);
+    }
+
+    #[test]
+    fn standing_still_does_not_lose_distance() {
+        let mut score = Score::default();
+        score.run(40);
+        score.run(-4);
+
+        assert_eq!(score.meters(), 1);
+    }
+}
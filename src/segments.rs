@@ -1,35 +1,36 @@
+use anyhow::{anyhow, Result};
 use std::rc::Rc;
 use web_sys::HtmlImageElement;
 
-use crate::engine::{Image, Point, Rect, SpriteSheet};
-use crate::game::{Barrier, Obstacle, Platform};
+use crate::engine::{Easing, Image, Motion, MotionPath, Point, Rect, SpriteSheet, Tilemap, Tileset};
+use crate::game::{jump_offset, rightmost, Barrier, Coin, Ground, Hanging, MovingPlatform, Obstacle, Platform, PlatformTiles};
+use crate::tiled::{TileLayer, TiledMap};
 
 const LOW_PLATFORM: i16 = 420;
 const HIGH_PLATFORM: i16 = 375;
 const FIRST_PLATFORM: i16 = 370;
 
 const STONE_ON_GROUND: i16 = 546;
+// 走っていると頭に当たり、スライディングならくぐれる高さ
+pub const STONE_AT_HEAD_HEIGHT: i16 = 476;
+const GROUND: i16 = 600;
+// 走っている男の子の胸の高さ。ジャンプの弧はここから始まる
+const COIN_ARC_BASE: i16 = 520;
+
+const GRASS_LEFT: u16 = 1;
+const GRASS_MIDDLE: u16 = 2;
+const GRASS_RIGHT: u16 = 3;
 
 const FLOATING_PLATFORM_SPRITES: [&str; 3] = ["13.png", "14.png", "15.png"];
-const PLATFORM_WIDTH: i16 = 384;
-const PLATFORM_HEIGHT: i16 = 93;
-const PLATFORM_EDGE_WIDTH: i16 = 60;
-const PLATFORM_EDGE_HEIGHT: i16 = 54;
-const FLOATING_PLATFORM_BOUNDING_BOXES: [Rect; 3] = [
-    Rect::new_from_x_y(0, 0, PLATFORM_EDGE_WIDTH, PLATFORM_EDGE_HEIGHT),
-    Rect::new_from_x_y(
-        PLATFORM_EDGE_WIDTH,
-        0,
-        PLATFORM_WIDTH - (PLATFORM_EDGE_WIDTH * 2),
-        PLATFORM_HEIGHT,
-    ),
-    Rect::new_from_x_y(
-        PLATFORM_WIDTH - PLATFORM_EDGE_WIDTH,
-        0,
-        PLATFORM_EDGE_WIDTH,
-        PLATFORM_EDGE_HEIGHT,
-    ),
-];
+const FLOATING_PLATFORM: PlatformTiles = PlatformTiles {
+    left: FLOATING_PLATFORM_SPRITES[0],
+    middle: FLOATING_PLATFORM_SPRITES[1],
+    right: FLOATING_PLATFORM_SPRITES[2],
+    edge_width: 60,
+    edge_height: 54,
+};
+const SHORT_PLATFORM_MIDDLE_TILES: usize = 1;
+const LONG_PLATFORM_MIDDLE_TILES: usize = 4;
 
 
 pub fn stone_and_platform(
@@ -38,7 +39,7 @@ pub fn stone_and_platform(
     offset_x: i16,
 ) -> Vec<Box<dyn Obstacle>> {
     const INITIAL_STONE_OFFSET: i16 = 300;
-    vec![
+    with_ground(offset_x, vec![
         Box::new(Barrier::new(Image::new(
             stone,
             Point {
@@ -52,8 +53,9 @@ pub fn stone_and_platform(
                 x: offset_x + FIRST_PLATFORM,
                 y: LOW_PLATFORM,
             },
+            SHORT_PLATFORM_MIDDLE_TILES,
         )),
-    ]
+    ])
 }
 
 pub fn platform_and_stone(
@@ -62,14 +64,80 @@ pub fn platform_and_stone(
     offset_x: i16,
 ) -> Vec<Box<dyn Obstacle>> {
     const INITIAL_STONE_OFFSET: i16 = 150;
-    vec![
+    with_ground(offset_x, vec![
+        Box::new(create_floating_platform(
+            sprite_sheet,
+            Point {
+                x: offset_x + FIRST_PLATFORM,
+                y: HIGH_PLATFORM,
+            },
+            SHORT_PLATFORM_MIDDLE_TILES,
+        )),
+        Box::new(Barrier::new(Image::new(
+            stone,
+            Point {
+                x: offset_x + INITIAL_STONE_OFFSET,
+                y: STONE_ON_GROUND,
+            },
+        ))),
+    ])
+}
+
+pub fn ground_block(tileset: Rc<Tileset>, offset_x: i16) -> Vec<Box<dyn Obstacle>> {
+    const INITIAL_BLOCK_OFFSET: i16 = 200;
+    let block_top = GROUND - tileset.tile_height();
+    with_ground(offset_x, vec![Box::new(Tilemap::new(
+        tileset,
+        3,
+        vec![GRASS_LEFT, GRASS_MIDDLE, GRASS_RIGHT],
+        Point {
+            x: offset_x + INITIAL_BLOCK_OFFSET,
+            y: block_top,
+        },
+    ))])
+}
+
+pub fn long_platform_over_stones(
+    stone: HtmlImageElement,
+    sprite_sheet: Rc<SpriteSheet>,
+    offset_x: i16,
+) -> Vec<Box<dyn Obstacle>> {
+    const FIRST_STONE_OFFSET: i16 = 450;
+    const SECOND_STONE_OFFSET: i16 = 750;
+    with_ground(offset_x, vec![
         Box::new(create_floating_platform(
             sprite_sheet,
             Point {
                 x: offset_x + FIRST_PLATFORM,
                 y: HIGH_PLATFORM,
             },
+            LONG_PLATFORM_MIDDLE_TILES,
         )),
+        Box::new(Barrier::new(Image::new(
+            stone.clone(),
+            Point {
+                x: offset_x + FIRST_STONE_OFFSET,
+                y: STONE_ON_GROUND,
+            },
+        ))),
+        Box::new(Barrier::new(Image::new(
+            stone,
+            Point {
+                x: offset_x + SECOND_STONE_OFFSET,
+                y: STONE_ON_GROUND,
+            },
+        ))),
+    ])
+}
+
+pub fn moving_platforms(
+    stone: HtmlImageElement,
+    sprite_sheet: Rc<SpriteSheet>,
+    offset_x: i16,
+) -> Vec<Box<dyn Obstacle>> {
+    const INITIAL_STONE_OFFSET: i16 = 300;
+    const SECOND_PLATFORM: i16 = 950;
+    with_ground(offset_x, vec![
         Box::new(Barrier::new(Image::new(
             stone,
             Point {
@@ -77,14 +145,323 @@ pub fn platform_and_stone(
                 y: STONE_ON_GROUND,
             },
         ))),
-    ]
+        Box::new(MovingPlatform::new(
+            create_floating_platform(
+                sprite_sheet.clone(),
+                Point {
+                    x: offset_x + FIRST_PLATFORM,
+                    y: LOW_PLATFORM,
+                },
+                SHORT_PLATFORM_MIDDLE_TILES,
+            ),
+            Motion::new(MotionPath::Oscillate {
+                amplitude: Point { x: 0, y: 40 },
+                period: 120,
+            }),
+        )),
+        Box::new(MovingPlatform::new(
+            create_floating_platform(
+                sprite_sheet,
+                Point {
+                    x: offset_x + SECOND_PLATFORM,
+                    y: HIGH_PLATFORM,
+                },
+                SHORT_PLATFORM_MIDDLE_TILES,
+            ),
+            Motion::new(MotionPath::Waypoints {
+                points: vec![Point { x: 0, y: 0 }, Point { x: 200, y: -30 }],
+                frames_per_leg: 90,
+                easing: Easing::EaseInOut,
+            }),
+        )),
+    ])
 }
 
-fn create_floating_platform(sprite_sheet: Rc<SpriteSheet>, position: Point) -> Platform {
-    Platform::new(
-        sprite_sheet,
-        position,
-        &FLOATING_PLATFORM_SPRITES,
-        &FLOATING_PLATFORM_BOUNDING_BOXES,
+pub fn pits(offset_x: i16) -> Vec<Box<dyn Obstacle>> {
+    const FIRST_PIT_OFFSET: i16 = 250;
+    const FIRST_PIT_WIDTH: i16 = 120;
+    const SECOND_PIT_OFFSET: i16 = 650;
+    const SECOND_PIT_WIDTH: i16 = 160;
+    // 二つ目の穴の向こうにも着地できるだけの地面を残す
+    const SEGMENT_WIDTH: i16 = 1000;
+    ground(
+        offset_x,
+        SEGMENT_WIDTH,
+        &[(FIRST_PIT_OFFSET, FIRST_PIT_WIDTH), (SECOND_PIT_OFFSET, SECOND_PIT_WIDTH)],
     )
-}
\ No newline at end of file
+}
+
+pub fn hanging_stones(stone: HtmlImageElement, offset_x: i16) -> Vec<Box<dyn Obstacle>> {
+    const FIRST_STONE_OFFSET: i16 = 300;
+    const SECOND_STONE_OFFSET: i16 = 420;
+    with_ground(offset_x, vec![
+        Box::new(Hanging::new(Image::new(
+            stone.clone(),
+            Point {
+                x: offset_x + FIRST_STONE_OFFSET,
+                y: STONE_AT_HEAD_HEIGHT,
+            },
+        ))),
+        Box::new(Hanging::new(Image::new(
+            stone,
+            Point {
+                x: offset_x + SECOND_STONE_OFFSET,
+                y: STONE_AT_HEAD_HEIGHT,
+            },
+        ))),
+    ])
+}
+
+// 石を飛び越えるジャンプの弧に沿ってコインを並べる
+pub fn coins_over_stone(
+    stone: HtmlImageElement,
+    coin_sheet: Rc<SpriteSheet>,
+    offset_x: i16,
+) -> Vec<Box<dyn Obstacle>> {
+    const STONE_OFFSET: i16 = 300;
+    const JUMP_START: i16 = 200;
+    const COIN_FRAMES: [i16; 7] = [8, 14, 20, 26, 32, 38, 44];
+    let mut obstacles: Vec<Box<dyn Obstacle>> = vec![Box::new(Barrier::new(Image::new(
+        stone,
+        Point {
+            x: offset_x + STONE_OFFSET,
+            y: STONE_ON_GROUND,
+        },
+    )))];
+    obstacles.extend(COIN_FRAMES.iter().map(|frames| {
+        let offset = jump_offset(*frames);
+        Box::new(Coin::new(
+            coin_sheet.clone(),
+            Point {
+                x: offset_x + JUMP_START + offset.x,
+                y: COIN_ARC_BASE + offset.y,
+            },
+        )) as Box<dyn Obstacle>
+    }));
+    with_ground(offset_x, obstacles)
+}
+
+// 穴の無い区間は先頭から一番右の障害物まで地面を敷く
+fn with_ground(offset_x: i16, mut obstacles: Vec<Box<dyn Obstacle>>) -> Vec<Box<dyn Obstacle>> {
+    let width = rightmost(&obstacles) - offset_x;
+    obstacles.append(&mut ground(offset_x, width, &[]));
+    obstacles
+}
+
+// gapsは区間の先頭からの位置と幅。そこだけ地面を敷かずに穴にする
+fn ground(offset_x: i16, width: i16, gaps: &[(i16, i16)]) -> Vec<Box<dyn Obstacle>> {
+    ground_spans(width, gaps)
+        .into_iter()
+        .map(|(x, span_width)| Box::new(Ground::new(offset_x + x, span_width)) as Box<dyn Obstacle>)
+        .collect()
+}
+
+fn ground_spans(width: i16, gaps: &[(i16, i16)]) -> Vec<(i16, i16)> {
+    let mut gaps = gaps.to_vec();
+    gaps.sort_by_key(|(x, _)| *x);
+
+    let mut spans = Vec::new();
+    let mut x = 0;
+    for (gap_x, gap_width) in gaps {
+        if gap_x > x {
+            spans.push((x, gap_x - x));
+        }
+        x = x.max(gap_x + gap_width);
+    }
+    if x < width {
+        spans.push((x, width - x));
+    }
+    spans
+}
+
+fn create_floating_platform(
+    sprite_sheet: Rc<SpriteSheet>,
+    position: Point,
+    middle_tiles: usize,
+) -> Platform {
+    Platform::from_tiles(sprite_sheet, position, &FLOATING_PLATFORM, middle_tiles)
+}
+
+// 幅に収まる中央タイルの数。タイルの幅はシートのフレームから取る
+fn middle_tile_count(width: i16, tiles: &PlatformTiles, tile_width: impl Fn(&str) -> Option<i16>) -> usize {
+    let edges = tile_width(tiles.left).unwrap_or(0) + tile_width(tiles.right).unwrap_or(0);
+    match tile_width(tiles.middle) {
+        Some(middle) if middle > 0 => ((width - edges) / middle).max(0) as usize,
+        _ => 0,
+    }
+}
+
+// Tiledで作ったセグメント。オブジェクトの種類は読み込み時に検証する
+enum SegmentObject {
+    Barrier(Rect),
+    Platform { bounds: Rect, sprites: Vec<String> },
+    // spritesが無い足場はオブジェクトの幅に合わせて浮き足場のタイルで組み立てる
+    FloatingPlatform(Rect),
+    // 穴は横方向の範囲だけを使い、そこには地面を敷かない
+    Pit(Rect),
+    Hanging(Rect),
+}
+
+pub struct TiledSegment {
+    map: TiledMap,
+    layers: Vec<TileLayer>,
+    objects: Vec<SegmentObject>,
+}
+
+impl TiledSegment {
+    pub fn new(map: TiledMap) -> Result<Self> {
+        let objects = map
+            .objects()
+            .map(|object| match object.kind() {
+                "barrier" => Ok(SegmentObject::Barrier(object.bounds())),
+                "platform" => Ok(match object.string_property("sprites") {
+                    Some(sprites) => SegmentObject::Platform {
+                        bounds: object.bounds(),
+                        sprites: sprites.split(',').map(|name| name.trim().to_string()).collect(),
+                    },
+                    None => SegmentObject::FloatingPlatform(object.bounds()),
+                }),
+                "pit" => Ok(SegmentObject::Pit(object.bounds())),
+                "hanging" => Ok(SegmentObject::Hanging(object.bounds())),
+                kind => Err(anyhow!(
+                    "Unknown object type '{}' for Tiled object id {}",
+                    kind,
+                    object.id
+                )),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let layers = map.tile_layers()?;
+
+        Ok(TiledSegment { map, layers, objects })
+    }
+
+    // マップの下端を地面に揃えて配置する
+    pub fn obstacles(
+        &self,
+        stone: HtmlImageElement,
+        sprite_sheet: Rc<SpriteSheet>,
+        tileset: Rc<Tileset>,
+        offset_x: i16,
+    ) -> Vec<Box<dyn Obstacle>> {
+        let origin = Point {
+            x: offset_x,
+            y: GROUND - self.map.pixel_height(),
+        };
+
+        let mut obstacles: Vec<Box<dyn Obstacle>> = self
+            .layers
+            .iter()
+            .map(|layer| Box::new(layer.tilemap(tileset.clone(), origin)) as Box<dyn Obstacle>)
+            .collect();
+
+        self.objects.iter().for_each(|object| match object {
+            SegmentObject::Barrier(bounds) => {
+                obstacles.push(Box::new(Barrier::new(Image::with_bounding_box(
+                    stone.clone(),
+                    Rect::new_from_x_y(
+                        origin.x + bounds.x(),
+                        origin.y + bounds.y(),
+                        bounds.width,
+                        bounds.height,
+                    ),
+                ))));
+            }
+            SegmentObject::Platform { bounds, sprites } => {
+                let sprite_names: Vec<&str> = sprites.iter().map(String::as_str).collect();
+                obstacles.push(Box::new(Platform::new(
+                    sprite_sheet.clone(),
+                    Point {
+                        x: origin.x + bounds.x(),
+                        y: origin.y + bounds.y(),
+                    },
+                    &sprite_names,
+                    &[Rect::new_from_x_y(0, 0, bounds.width, bounds.height)],
+                )));
+            }
+            SegmentObject::FloatingPlatform(bounds) => {
+                let middle_tiles = middle_tile_count(bounds.width, &FLOATING_PLATFORM, |sprite_name| {
+                    sprite_sheet.cell(sprite_name).map(|cell| cell.frame.w)
+                });
+                obstacles.push(Box::new(create_floating_platform(
+                    sprite_sheet.clone(),
+                    Point {
+                        x: origin.x + bounds.x(),
+                        y: origin.y + bounds.y(),
+                    },
+                    middle_tiles,
+                )));
+            }
+            SegmentObject::Pit(_) => {}
+            SegmentObject::Hanging(bounds) => {
+                obstacles.push(Box::new(Hanging::new(Image::with_bounding_box(
+                    stone.clone(),
+                    Rect::new_from_x_y(
+                        origin.x + bounds.x(),
+                        origin.y + bounds.y(),
+                        bounds.width,
+                        bounds.height,
+                    ),
+                ))));
+            }
+        });
+
+        let gaps: Vec<(i16, i16)> = self
+            .objects
+            .iter()
+            .filter_map(|object| match object {
+                SegmentObject::Pit(bounds) => Some((bounds.x(), bounds.width)),
+                _ => None,
+            })
+            .collect();
+        obstacles.append(&mut ground(origin.x, self.map.pixel_width(), &gaps));
+
+        obstacles
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unknown_tiled_object_type_names_the_object_id() {
+        let map: TiledMap = serde_json::from_str(
+            r#"{
+                "width": 1, "height": 1, "tilewidth": 128, "tileheight": 128,
+                "layers": [{"type": "objectgroup", "objects": [
+                    {"id": 1, "type": "barrier", "x": 0, "y": 0, "width": 90, "height": 54},
+                    {"id": 7, "type": "spring", "x": 0, "y": 0}
+                ]}]
+            }"#,
+        )
+        .unwrap();
+
+        let err = TiledSegment::new(map).err().expect("spring should be rejected");
+
+        assert_eq!(err.to_string(), "Unknown object type 'spring' for Tiled object id 7");
+    }
+
+    #[test]
+    fn floating_platform_middle_tiles_follow_the_sheet_frames() {
+        let tile_width = |sprite_name: &str| match sprite_name {
+            "13.png" | "15.png" => Some(100),
+            "14.png" => Some(120),
+            _ => None,
+        };
+
+        assert_eq!(middle_tile_count(560, &FLOATING_PLATFORM, tile_width), 3);
+        assert_eq!(middle_tile_count(150, &FLOATING_PLATFORM, tile_width), 0);
+        assert_eq!(middle_tile_count(560, &FLOATING_PLATFORM, |_| None), 0);
+    }
+
+    #[test]
+    fn ground_spans_leave_out_the_gaps() {
+        assert_eq!(ground_spans(1000, &[]), vec![(0, 1000)]);
+        assert_eq!(
+            ground_spans(1000, &[(650, 160), (250, 120)]),
+            vec![(0, 250), (370, 280), (810, 190)]
+        );
+        // 端の穴や重なった穴の分は地面にしない
+        assert_eq!(ground_spans(500, &[(0, 100), (80, 60), (400, 100)]), vec![(140, 260)]);
+    }
+}
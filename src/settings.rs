@@ -0,0 +1,181 @@
+/* 設定画面で変えられる項目。Storageに JSON で保存する */
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::browser::Storage;
+
+const SETTINGS_KEY: &str = "walk_the_dog.settings";
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Settings {
+    pub music_volume: f32,
+    pub sfx_volume: f32,
+    pub bindings: KeyBindings,
+    pub show_fps: bool,
+    pub reduced_motion: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            music_volume: 1.0,
+            sfx_volume: 1.0,
+            bindings: KeyBindings::default(),
+            // 開発中のビルドでは最初からフレームレートを出す
+            show_fps: cfg!(debug_assertions),
+            reduced_motion: false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    Run,
+    Jump,
+    Slide,
+    Pause,
+}
+
+pub const ACTIONS: [Action; 4] = [Action::Run, Action::Jump, Action::Slide, Action::Pause];
+
+impl Action {
+    pub fn label(&self) -> &'static str {
+        match self {
+            Action::Run => "RUN",
+            Action::Jump => "JUMP",
+            Action::Slide => "SLIDE",
+            Action::Pause => "PAUSE",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct KeyBindings {
+    pub run: String,
+    pub jump: String,
+    pub slide: String,
+    pub pause: String,
+}
+
+impl Default for KeyBindings {
+    fn default() -> Self {
+        KeyBindings {
+            run: "ArrowRight".to_string(),
+            jump: "Space".to_string(),
+            slide: "ArrowDown".to_string(),
+            pause: "KeyP".to_string(),
+        }
+    }
+}
+
+impl KeyBindings {
+    pub fn key(&self, action: Action) -> &str {
+        match action {
+            Action::Run => &self.run,
+            Action::Jump => &self.jump,
+            Action::Slide => &self.slide,
+            Action::Pause => &self.pause,
+        }
+    }
+
+    // 他の操作に同じキーが使われていたら入れ替える
+    pub fn bind(&mut self, action: Action, code: &str) {
+        let previous = self.key(action).to_string();
+        for other in ACTIONS {
+            if other != action && self.key(other) == code {
+                *self.key_mut(other) = previous.clone();
+            }
+        }
+        *self.key_mut(action) = code.to_string();
+    }
+
+    fn key_mut(&mut self, action: Action) -> &mut String {
+        match action {
+            Action::Run => &mut self.run,
+            Action::Jump => &mut self.jump,
+            Action::Slide => &mut self.slide,
+            Action::Pause => &mut self.pause,
+        }
+    }
+}
+
+impl Settings {
+    // 保存された設定が壊れていたら初期値を使う
+    pub fn load(storage: &mut dyn Storage) -> Self {
+        match storage.get(SETTINGS_KEY) {
+            Ok(Some(json)) => serde_json::from_str(&json).unwrap_or_else(|err| {
+                log!("Could not parse settings {:#?}", err);
+                Settings::default()
+            }),
+            Ok(None) => Settings::default(),
+            Err(err) => {
+                log!("Could not load settings {:#?}", err);
+                Settings::default()
+            }
+        }
+    }
+
+    pub fn save(&self, storage: &mut dyn Storage) -> Result<()> {
+        storage.set(SETTINGS_KEY, &serde_json::to_string(self)?)
+    }
+}
+
+// ビットマップフォントは大文字しか無いので、KeyboardEvent.codeを画面用に直す
+pub fn key_label(code: &str) -> String {
+    let name = code
+        .strip_prefix("Arrow")
+        .or_else(|| code.strip_prefix("Key"))
+        .or_else(|| code.strip_prefix("Digit"))
+        .unwrap_or(code);
+    name.to_uppercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::browser::MemoryStorage;
+
+    #[test]
+    fn settings_round_trip_through_storage() {
+        let mut storage = MemoryStorage::default();
+        let settings = Settings {
+            music_volume: 0.3,
+            show_fps: true,
+            ..Settings::default()
+        };
+
+        settings.save(&mut storage).unwrap();
+
+        assert_eq!(Settings::load(&mut storage), settings);
+    }
+
+    #[test]
+    fn missing_fields_fall_back_to_defaults() {
+        let mut storage = MemoryStorage::default();
+        storage.set(SETTINGS_KEY, r#"{"sfxVolume": 0.5}"#).unwrap();
+
+        let settings = Settings::load(&mut storage);
+
+        assert_eq!(settings.sfx_volume, 0.5);
+        assert_eq!(settings.bindings, KeyBindings::default());
+    }
+
+    #[test]
+    fn binding_a_used_key_swaps_it() {
+        let mut bindings = KeyBindings::default();
+
+        bindings.bind(Action::Jump, "ArrowDown");
+
+        assert_eq!(bindings.jump, "ArrowDown");
+        assert_eq!(bindings.slide, "Space");
+    }
+
+    #[test]
+    fn key_labels_drop_the_code_prefix() {
+        assert_eq!(key_label("ArrowRight"), "RIGHT");
+        assert_eq!(key_label("KeyP"), "P");
+        assert_eq!(key_label("Space"), "SPACE");
+    }
+}
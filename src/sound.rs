@@ -2,7 +2,7 @@ use anyhow::{anyhow, Result};
 
 use web_sys::{AudioContext, AudioBuffer, AudioBufferSourceNode, AudioDestinationNode, AudioNode, GainNode};
 use js_sys::ArrayBuffer;
-use wasm_bindgen::{JsValue, JsCast};
+use wasm_bindgen::JsCast;
 use wasm_bindgen_futures::JsFuture;
 
 
@@ -10,11 +10,25 @@ pub fn create_audio_context() -> Result<AudioContext> {
     AudioContext::new().map_err(|err| anyhow!("Could not create audio context: {:#?}", err))
 }
 
+// まとめて音量を変えるためのチャンネル。destinationにつながっている
+pub fn create_channel(ctx: &AudioContext) -> Result<GainNode> {
+    let channel = ctx.create_gain().map_err(|err| anyhow!("Error creating gain node: {:#?}", err))?;
+    channel
+        .connect_with_audio_node(&ctx.destination())
+        .map_err(|err| anyhow!("Error connecting channel with destination: {:#?}", err))?;
+    Ok(channel)
+}
+
+pub fn set_volume(channel: &GainNode, volume: f32) {
+    channel.gain().set_value(volume);
+}
+
 fn create_buffer_source(ctx: &AudioContext) -> Result<AudioBufferSourceNode> {
     ctx.create_buffer_source()
         .map_err(|err| anyhow!("Error creating buffer source: {:#?}", err))
 }
 
+#[allow(dead_code)]
 fn connect_with_audio_node(
     buffer_source: &AudioBufferSourceNode,
     destination: &AudioDestinationNode,
@@ -24,9 +38,9 @@ fn connect_with_audio_node(
         .map_err(|err| anyhow!("Error connecting buffer source with destination: {:#?}", err))
 }
 
-pub fn play_sound(ctx: &AudioContext, buffer: &AudioBuffer, looping: LOOPING) -> Result<()> {
-    let track_source = create_track_source(ctx, buffer)?;
-    if matches!(looping, LOOPING::YES) {
+pub fn play_sound(ctx: &AudioContext, buffer: &AudioBuffer, looping: Looping, channel: &GainNode) -> Result<()> {
+    let track_source = create_track_source(ctx, buffer, channel)?;
+    if matches!(looping, Looping::Yes) {
         track_source.set_loop(true);
     }
 
@@ -37,30 +51,31 @@ pub fn play_sound(ctx: &AudioContext, buffer: &AudioBuffer, looping: LOOPING) ->
 
 fn create_track_source(
     ctx: &AudioContext,
-    buffer: &AudioBuffer)
+    buffer: &AudioBuffer,
+    channel: &GainNode)
 -> Result<AudioBufferSourceNode> {
     let track_source = create_buffer_source(ctx)?;
-    track_source.set_buffer(Some(&buffer));
+    track_source.set_buffer(Some(buffer));
 
     // 音量の調節をする
     let gain: GainNode = ctx.create_gain().map_err(|err| anyhow!("Error creating gain node: {:#?}", err))?;
     gain.gain().set_value(0.05);
-    // 接続順序: track_source -> gain -> destination
+    // 接続順序: track_source -> gain -> channel -> destination
     track_source.connect_with_audio_node(&gain).map_err(|err| anyhow!("Error connecting source with gain node: {:#?}", err))?;
-    gain.connect_with_audio_node(&ctx.destination()).map_err(|err| anyhow!("Error connecting gain node with destination: {:#?}", err))?;
+    gain.connect_with_audio_node(channel).map_err(|err| anyhow!("Error connecting gain node with channel: {:#?}", err))?;
 
     //connect_with_audio_node(&track_source, &ctx.destination())?;
     Ok(track_source)
 }
 
-pub enum LOOPING {
-    YES,
-    NO,
+pub enum Looping {
+    Yes,
+    No,
 }
 
 pub async fn decode_audio_data(ctx: &AudioContext, array_buffer: &ArrayBuffer) -> Result<AudioBuffer> {
     JsFuture::from(
-        ctx.decode_audio_data(&array_buffer)
+        ctx.decode_audio_data(array_buffer)
             .map_err(|err| anyhow!("Error decoding audio data: {:#?}", err))?,
     )
     .await
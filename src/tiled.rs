@@ -0,0 +1,268 @@
+/* Tiledエディタで書き出したJSONマップの読み込み */
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use std::rc::Rc;
+
+use crate::engine::{Point, Rect, TileFlip, Tilemap, Tileset};
+
+// gidの上位ビットは反転フラグ
+const FLIP_FLAGS: u32 = 0xE000_0000;
+const FLIPPED_HORIZONTALLY: u32 = 0x8000_0000;
+const FLIPPED_VERTICALLY: u32 = 0x4000_0000;
+// 対角線での反転(90度回転)は描けないので読み込み時に断る
+const FLIPPED_DIAGONALLY: u32 = 0x2000_0000;
+
+#[derive(Deserialize, Clone)]
+pub struct TiledMap {
+    pub width: usize,
+    pub height: usize,
+    pub tilewidth: i16,
+    pub tileheight: i16,
+    pub layers: Vec<TiledLayer>,
+    #[serde(default)]
+    pub tilesets: Vec<TiledTilesetRef>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct TiledTilesetRef {
+    pub firstgid: u32,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum TiledLayer {
+    TileLayer(TiledTileLayer),
+    ObjectGroup(TiledObjectGroup),
+    #[serde(other)]
+    Unsupported,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct TiledTileLayer {
+    pub width: usize,
+    pub data: Vec<u32>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct TiledObjectGroup {
+    pub objects: Vec<TiledObject>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct TiledObject {
+    pub id: u32,
+    // Tiled 1.9ではclass、それ以外のバージョンではtypeに入る
+    #[serde(default, rename = "type")]
+    object_type: String,
+    #[serde(default)]
+    class: String,
+    pub x: f32,
+    pub y: f32,
+    #[serde(default)]
+    pub width: f32,
+    #[serde(default)]
+    pub height: f32,
+    #[serde(default)]
+    pub gid: Option<u32>,
+    #[serde(default)]
+    pub properties: Vec<TiledProperty>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct TiledProperty {
+    pub name: String,
+    pub value: TiledPropertyValue,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum TiledPropertyValue {
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    String(String),
+}
+
+impl TiledMap {
+    pub fn pixel_width(&self) -> i16 {
+        self.width as i16 * self.tilewidth
+    }
+
+    pub fn pixel_height(&self) -> i16 {
+        self.height as i16 * self.tileheight
+    }
+
+    fn first_gid(&self) -> u32 {
+        self.tilesets.first().map(|tileset| tileset.firstgid).unwrap_or(1)
+    }
+
+    // タイルレイヤーをTilesetのIDと反転に変換する
+    pub fn tile_layers(&self) -> Result<Vec<TileLayer>> {
+        let first_gid = self.first_gid();
+        self.layers
+            .iter()
+            .filter_map(|layer| match layer {
+                TiledLayer::TileLayer(layer) => Some(layer),
+                _ => None,
+            })
+            .map(|layer| {
+                let (tiles, flips) = layer
+                    .data
+                    .iter()
+                    .map(|gid| tile_id(*gid, first_gid))
+                    .collect::<Result<Vec<_>>>()?
+                    .into_iter()
+                    .unzip();
+                Ok(TileLayer {
+                    columns: layer.width,
+                    tiles,
+                    flips,
+                })
+            })
+            .collect()
+    }
+
+    pub fn objects(&self) -> impl Iterator<Item = &TiledObject> {
+        self.layers
+            .iter()
+            .filter_map(|layer| match layer {
+                TiledLayer::ObjectGroup(group) => Some(group.objects.iter()),
+                _ => None,
+            })
+            .flatten()
+    }
+}
+
+// gidをTilesetのIDと反転に分ける
+fn tile_id(gid: u32, first_gid: u32) -> Result<(u16, TileFlip)> {
+    if gid & FLIPPED_DIAGONALLY != 0 {
+        return Err(anyhow!("Tile gid {} is flipped diagonally, which is not supported", gid));
+    }
+    let flip = TileFlip {
+        horizontal: gid & FLIPPED_HORIZONTALLY != 0,
+        vertical: gid & FLIPPED_VERTICALLY != 0,
+    };
+    let id = match gid & !FLIP_FLAGS {
+        0 => Tileset::EMPTY,
+        gid => u16::try_from((gid + 1).saturating_sub(first_gid))
+            .map_err(|_| anyhow!("Tile gid {} is too large for a tile id", gid))?,
+    };
+    Ok((id, flip))
+}
+
+// 1枚のタイルレイヤー。tilesとflipsは同じ並び
+#[derive(Clone, Debug, PartialEq)]
+pub struct TileLayer {
+    pub columns: usize,
+    pub tiles: Vec<u16>,
+    pub flips: Vec<TileFlip>,
+}
+
+impl TileLayer {
+    pub fn tilemap(&self, tileset: Rc<Tileset>, origin: Point) -> Tilemap {
+        Tilemap::new(tileset, self.columns, self.tiles.clone(), origin).with_flips(self.flips.clone())
+    }
+}
+
+impl TiledObject {
+    pub fn kind(&self) -> &str {
+        if self.object_type.is_empty() {
+            &self.class
+        } else {
+            &self.object_type
+        }
+    }
+
+    // タイルオブジェクトは左下が原点なので左上に揃える
+    pub fn bounds(&self) -> Rect {
+        let top = match self.gid {
+            Some(_) => self.y - self.height,
+            None => self.y,
+        };
+        Rect::new_from_x_y(
+            self.x.round() as i16,
+            top.round() as i16,
+            self.width.round() as i16,
+            self.height.round() as i16,
+        )
+    }
+
+    pub fn property(&self, name: &str) -> Option<&TiledPropertyValue> {
+        self.properties
+            .iter()
+            .find(|property| property.name == name)
+            .map(|property| &property.value)
+    }
+
+    pub fn string_property(&self, name: &str) -> Option<&str> {
+        match self.property(name) {
+            Some(TiledPropertyValue::String(value)) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAP: &str = r#"{
+        "width": 3, "height": 2, "tilewidth": 128, "tileheight": 128,
+        "tilesets": [{"firstgid": 5, "source": "tiles.tsx"}],
+        "layers": [
+            {"type": "tilelayer", "name": "ground", "width": 3, "height": 2,
+             "data": [0, 0, 0, 5, 2147483654, 7]},
+            {"type": "imagelayer", "name": "sky"},
+            {"type": "objectgroup", "name": "obstacles", "objects": [
+                {"id": 3, "type": "barrier", "x": 10, "y": 20, "width": 90, "height": 54},
+                {"id": 4, "class": "platform", "x": 0, "y": 100, "width": 50, "height": 40,
+                 "gid": 12, "properties": [{"name": "sprites", "type": "string", "value": "13.png"}]}
+            ]}
+        ]
+    }"#;
+
+    #[test]
+    fn tile_layers_convert_gids_to_tileset_ids() {
+        let map: TiledMap = serde_json::from_str(MAP).unwrap();
+        let layers = map.tile_layers().unwrap();
+
+        assert_eq!(layers.len(), 1);
+        assert_eq!(layers[0].columns, 3);
+        assert_eq!(layers[0].tiles, vec![0, 0, 0, 1, 2, 3]);
+        assert_eq!(map.pixel_height(), 256);
+    }
+
+    #[test]
+    fn flip_bits_are_kept_with_the_tile() {
+        let map: TiledMap = serde_json::from_str(MAP).unwrap();
+        let flips = &map.tile_layers().unwrap()[0].flips;
+
+        assert_eq!(flips[4], TileFlip { horizontal: true, vertical: false });
+        assert_eq!(flips[5], TileFlip::default());
+        assert_eq!(
+            tile_id(FLIPPED_VERTICALLY | 6, 5).unwrap(),
+            (2, TileFlip { horizontal: false, vertical: true })
+        );
+    }
+
+    #[test]
+    fn diagonal_flips_and_oversized_gids_are_rejected() {
+        assert!(tile_id(FLIPPED_DIAGONALLY | 6, 5).is_err());
+        assert_eq!(
+            tile_id(70_000, 1).unwrap_err().to_string(),
+            "Tile gid 70000 is too large for a tile id"
+        );
+    }
+
+    #[test]
+    fn objects_read_type_or_class_and_properties() {
+        let map: TiledMap = serde_json::from_str(MAP).unwrap();
+        let objects: Vec<&TiledObject> = map.objects().collect();
+
+        assert_eq!(objects[0].kind(), "barrier");
+        assert_eq!(objects[1].kind(), "platform");
+        assert_eq!(objects[1].bounds().y(), 60);
+        assert_eq!(objects[1].string_property("sprites"), Some("13.png"));
+        assert_eq!(objects[0].string_property("sprites"), None);
+    }
+}
@@ -0,0 +1,114 @@
+/* #ui の上に重ねるDOMのメニュー。HTML文字列を使わずに要素を組み立て、
+   ウィジェットごとに型の付いたイベントを返す。Panelを捨てると要素とリスナーも消える */
+use anyhow::{anyhow, Result};
+use futures::channel::mpsc::{unbounded, UnboundedReceiver};
+use wasm_bindgen::JsCast;
+use web_sys::{Element, Event, HtmlElement, HtmlInputElement};
+
+use crate::browser::{self, Listeners};
+
+pub struct Panel {
+    element: HtmlElement,
+    listeners: Listeners,
+}
+
+impl Panel {
+    // parentの末尾に追加するので、いくつでも同時に出せる
+    pub fn new(parent: &Element, class: &str) -> Result<Self> {
+        let element = browser::create_html_element("div")?;
+        element.set_class_name(class);
+        parent
+            .append_child(&element)
+            .map_err(|err| anyhow!("Could not add panel {:#?}", err))?;
+        Ok(Panel {
+            element,
+            listeners: Listeners::default(),
+        })
+    }
+
+    pub fn label(&mut self, text: &str) -> Result<()> {
+        let label = self.append("span")?;
+        label.set_class_name("label");
+        label.set_text_content(Some(text));
+        Ok(())
+    }
+
+    pub fn button(&mut self, text: &str) -> Result<UnboundedReceiver<()>> {
+        let button = self.append("button")?;
+        button.set_text_content(Some(text));
+        let (sender, receiver) = unbounded();
+        self.listeners.add(&button, "click", move |_: Event| {
+            let _ = sender.unbounded_send(());
+        })?;
+        Ok(receiver)
+    }
+
+    // 0.0から1.0のスライダー。動かすたびに値が届く
+    pub fn slider(&mut self, text: &str, value: f32) -> Result<UnboundedReceiver<f32>> {
+        let container = self.append("label")?;
+        container.set_class_name("slider");
+        container.set_text_content(Some(text));
+        let input = browser::create_html_element("input")?
+            .dyn_into::<HtmlInputElement>()
+            .map_err(|element| anyhow!("Error converting {:#?} to HtmlInputElement", element))?;
+        input.set_type("range");
+        input.set_min("0");
+        input.set_max("1");
+        input.set_step("0.1");
+        input.set_value(&value.to_string());
+        container
+            .append_child(&input)
+            .map_err(|err| anyhow!("Could not add slider {:#?}", err))?;
+
+        let (sender, receiver) = unbounded();
+        let slider = input.clone();
+        self.listeners.add(&input, "input", move |_: Event| {
+            if let Ok(value) = slider.value().parse() {
+                let _ = sender.unbounded_send(value);
+            }
+        })?;
+        Ok(receiver)
+    }
+
+    // 選ばれた項目の番号が届く
+    pub fn list(&mut self, items: &[String]) -> Result<UnboundedReceiver<usize>> {
+        let list = self.append("ul")?;
+        list.set_class_name("list");
+        let (sender, receiver) = unbounded();
+        for (index, item) in items.iter().enumerate() {
+            let row = browser::create_html_element("li")?;
+            row.set_text_content(Some(item));
+            list.append_child(&row)
+                .map_err(|err| anyhow!("Could not add list item {:#?}", err))?;
+            let sender = sender.clone();
+            self.listeners.add(&row, "click", move |_: Event| {
+                let _ = sender.unbounded_send(index);
+            })?;
+        }
+        Ok(receiver)
+    }
+
+    fn append(&self, tag: &str) -> Result<HtmlElement> {
+        let element = browser::create_html_element(tag)?;
+        self.element
+            .append_child(&element)
+            .map_err(|err| anyhow!("Could not add {} {:#?}", tag, err))?;
+        Ok(element)
+    }
+}
+
+// リスナーはlistenersと一緒に外れる
+impl Drop for Panel {
+    fn drop(&mut self) {
+        self.element.remove();
+    }
+}
+
+// 受け取ったイベントのうち最後のものだけを返す
+pub fn latest<T>(receiver: &mut UnboundedReceiver<T>) -> Option<T> {
+    let mut latest = None;
+    while let Ok(Some(event)) = receiver.try_next() {
+        latest = Some(event);
+    }
+    latest
+}